@@ -1,8 +1,9 @@
 use alloc::vec::Vec;
 
-use uefi::boot;
+use uefi::boot::{self, AllocateType, MemoryType};
 use uefi::fs::{Error as FsError, FileSystem};
 use uefi::prelude::*;
+use uefi::{guid, Guid};
 
 use crate::elf_loader::load_kernel_elf;
 use crate::error::BootError;
@@ -18,13 +19,21 @@ const KERNEL_CANDIDATE_PATHS: [(&str, &uefi::CStr16); 4] = [
     ("EFI\\BOOT\\kernel.elf", cstr16!("EFI\\BOOT\\kernel.elf")),
 ];
 
+const ACPI2_RSDP_GUID: Guid = guid!("8868e871-e4f1-11d3-bc22-0080c7c3c88a");
+const ACPI1_RSDP_GUID: Guid = guid!("eb9d2d30-2d88-11d3-9a16-0090273fc14d");
+
+// Startup IPIs can only target a 4 KiB page below 1 MiB.
+const AP_TRAMPOLINE_MAX_ADDR: u64 = 0x000F_FFFF;
+
 pub fn boot_kernel() -> Result<(), BootError> {
     let kernel_image = read_kernel_image()?;
     let (entry_point, segment_count) = load_kernel_elf(&kernel_image)?;
     if segment_count == 0 {
         return Err(BootError::InvalidElf("no PT_LOAD segments"));
     }
-    let boot_info = gui::prepare_boot_info()?;
+    let mut boot_info = gui::prepare_boot_info()?;
+    boot_info.acpi_rsdp_addr = find_acpi_rsdp();
+    boot_info.ap_trampoline_addr = allocate_ap_trampoline();
     let boot_info_ptr = gui::allocate_boot_info(boot_info)?;
 
     uefi::println!("kernel entry point: {entry_point:#x}");
@@ -37,6 +46,8 @@ pub fn boot_kernel() -> Result<(), BootError> {
         boot_info.framebuffer.addr,
         boot_info.framebuffer.back_buffer_addr
     );
+    uefi::println!("ACPI RSDP: {:#x}", boot_info.acpi_rsdp_addr);
+    uefi::println!("AP trampoline: {:#x}", boot_info.ap_trampoline_addr);
     uefi::println!("exiting boot services");
 
     exit_boot_services_and_jump(entry_point, boot_info_ptr)
@@ -73,6 +84,28 @@ fn read_kernel_image() -> Result<Vec<u8>, BootError> {
     Err(BootError::ReadKernel(Status::NOT_FOUND))
 }
 
+/// Returns the ACPI RSDP from the UEFI configuration table, preferring the
+/// ACPI 2.0 entry. Zero means the firmware published no ACPI tables.
+fn find_acpi_rsdp() -> u64 {
+    uefi::system::with_config_table(|entries| {
+        [ACPI2_RSDP_GUID, ACPI1_RSDP_GUID]
+            .iter()
+            .find_map(|guid| entries.iter().find(|entry| entry.guid == *guid))
+            .map_or(0, |entry| entry.address as u64)
+    })
+}
+
+/// Reserves a low-memory page for the kernel's application processor startup
+/// code. Zero means none was available and the kernel stays single-CPU.
+fn allocate_ap_trampoline() -> u64 {
+    boot::allocate_pages(
+        AllocateType::MaxAddress(AP_TRAMPOLINE_MAX_ADDR),
+        MemoryType::LOADER_DATA,
+        1,
+    )
+    .map_or(0, |ptr| ptr.as_ptr() as u64)
+}
+
 fn fs_error_status(err: &FsError) -> Status {
     match err {
         FsError::Io(io) => io.uefi_error.status(),
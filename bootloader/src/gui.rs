@@ -6,7 +6,7 @@ use uefi::proto::console::gop::{FrameBuffer, GraphicsOutput, PixelFormat};
 use crate::error::BootError;
 
 pub const BOOT_INFO_MAGIC: u64 = 0x5255_5354_4F53_4749; // "RUSTOSGI"
-pub const BOOT_INFO_VERSION: u32 = 1;
+pub const BOOT_INFO_VERSION: u32 = 3;
 const PAGE_SIZE: usize = 4096;
 
 #[repr(u32)]
@@ -40,6 +40,8 @@ pub struct BootInfo {
     pub version: u32,
     pub _reserved0: u32,
     pub framebuffer: FramebufferInfo,
+    pub acpi_rsdp_addr: u64,
+    pub ap_trampoline_addr: u64,
 }
 
 pub fn prepare_boot_info() -> Result<BootInfo, BootError> {
@@ -77,6 +79,8 @@ pub fn prepare_boot_info() -> Result<BootInfo, BootError> {
         version: BOOT_INFO_VERSION,
         _reserved0: 0,
         framebuffer: fb_info,
+        acpi_rsdp_addr: 0,
+        ap_trampoline_addr: 0,
     })
 }
 
@@ -0,0 +1,191 @@
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+
+// MADT: header, local APIC address and flags, then variable-length entries.
+const MADT_ENTRIES_OFFSET: usize = mem::size_of::<SdtHeader>() + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// FADT: CMOS index of the RTC century register, 0 if there is none.
+const FADT_CENTURY_OFFSET: usize = 108;
+
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+static ROOT_IS_XSDT: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+ fields.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// ACPI Generic Address Structure.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+
+fn checksum_ok(addr: usize, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Validates the RSDP handed over by the bootloader and remembers the root
+/// system description table. Returns `false` if no usable tables exist.
+pub fn init(rsdp_addr: u64) -> bool {
+    if rsdp_addr == 0 {
+        return false;
+    }
+
+    let rsdp = unsafe { ptr::read_unaligned(rsdp_addr as *const Rsdp) };
+    if &rsdp.signature != RSDP_SIGNATURE || !checksum_ok(rsdp_addr as usize, RSDP_V1_LENGTH) {
+        return false;
+    }
+
+    let (root, is_xsdt) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        if !checksum_ok(rsdp_addr as usize, rsdp.length as usize) {
+            return false;
+        }
+        (rsdp.xsdt_address, true)
+    } else {
+        (rsdp.rsdt_address as u64, false)
+    };
+
+    if root == 0 || table_header(root).is_none() {
+        return false;
+    }
+
+    ROOT_IS_XSDT.store(is_xsdt, Ordering::Release);
+    ROOT_TABLE.store(root, Ordering::Release);
+    true
+}
+
+fn table_header(addr: u64) -> Option<SdtHeader> {
+    let header = unsafe { ptr::read_unaligned(addr as *const SdtHeader) };
+    let length = header.length as usize;
+    if length < mem::size_of::<SdtHeader>() || !checksum_ok(addr as usize, length) {
+        return None;
+    }
+    Some(header)
+}
+
+/// Finds a table by its four-byte signature (for example `b"HPET"`) and
+/// returns its physical address. Tables are identity mapped.
+pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let root = ROOT_TABLE.load(Ordering::Acquire);
+    if root == 0 {
+        return None;
+    }
+
+    let root_header = table_header(root)?;
+    let entry_size = if ROOT_IS_XSDT.load(Ordering::Acquire) {
+        mem::size_of::<u64>()
+    } else {
+        mem::size_of::<u32>()
+    };
+    let entries_base = root as usize + mem::size_of::<SdtHeader>();
+    let entry_count = (root_header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+
+    for index in 0..entry_count {
+        let entry_addr = entries_base + index * entry_size;
+        let table = unsafe {
+            if entry_size == mem::size_of::<u64>() {
+                ptr::read_unaligned(entry_addr as *const u64)
+            } else {
+                ptr::read_unaligned(entry_addr as *const u32) as u64
+            }
+        };
+        if table == 0 {
+            continue;
+        }
+
+        if let Some(header) = table_header(table)
+            && &header.signature == signature
+        {
+            return Some(table);
+        }
+    }
+    None
+}
+
+/// CMOS register holding the RTC century, if the FADT names one.
+pub fn rtc_century_register() -> Option<u8> {
+    let fadt = find_table(b"FACP")?;
+    let header = table_header(fadt)?;
+    if (header.length as usize) <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+    let register = unsafe { ptr::read((fadt as usize + FADT_CENTURY_OFFSET) as *const u8) };
+    (register != 0).then_some(register)
+}
+
+/// Local APIC IDs of all usable processors listed in the MADT, including the
+/// bootstrap processor.
+pub fn processor_apic_ids() -> Vec<u32> {
+    let mut ids = Vec::new();
+    let Some(madt) = find_table(b"APIC") else {
+        return ids;
+    };
+    let Some(header) = table_header(madt) else {
+        return ids;
+    };
+
+    let end = madt as usize + header.length as usize;
+    let mut entry = madt as usize + MADT_ENTRIES_OFFSET;
+    while entry + 2 <= end {
+        let (kind, length) = unsafe {
+            (
+                ptr::read(entry as *const u8),
+                ptr::read((entry + 1) as *const u8),
+            )
+        };
+        if length < 2 || entry + length as usize > end {
+            break;
+        }
+
+        if kind == MADT_LOCAL_APIC && length >= 8 {
+            let apic_id = unsafe { ptr::read((entry + 3) as *const u8) };
+            let flags = unsafe { ptr::read_unaligned((entry + 4) as *const u32) };
+            if flags & (MADT_LOCAL_APIC_ENABLED | MADT_LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                ids.push(apic_id as u32);
+            }
+        }
+        entry += length as usize;
+    }
+    ids
+}
@@ -3,24 +3,6 @@ use core::arch::global_asm;
 global_asm!(
     r#"
     .macro SAVE_CONTEXT
-        sub rsp, 0x100
-        movdqu [rsp + 0x00], xmm0
-        movdqu [rsp + 0x10], xmm1
-        movdqu [rsp + 0x20], xmm2
-        movdqu [rsp + 0x30], xmm3
-        movdqu [rsp + 0x40], xmm4
-        movdqu [rsp + 0x50], xmm5
-        movdqu [rsp + 0x60], xmm6
-        movdqu [rsp + 0x70], xmm7
-        movdqu [rsp + 0x80], xmm8
-        movdqu [rsp + 0x90], xmm9
-        movdqu [rsp + 0xA0], xmm10
-        movdqu [rsp + 0xB0], xmm11
-        movdqu [rsp + 0xC0], xmm12
-        movdqu [rsp + 0xD0], xmm13
-        movdqu [rsp + 0xE0], xmm14
-        movdqu [rsp + 0xF0], xmm15
-
         push r15
         push r14
         push r13
@@ -39,23 +21,6 @@ global_asm!(
     .endm
 
     .macro RESTORE_CONTEXT
-        movdqu xmm0, [rsp + 0x78]
-        movdqu xmm1, [rsp + 0x88]
-        movdqu xmm2, [rsp + 0x98]
-        movdqu xmm3, [rsp + 0xA8]
-        movdqu xmm4, [rsp + 0xB8]
-        movdqu xmm5, [rsp + 0xC8]
-        movdqu xmm6, [rsp + 0xD8]
-        movdqu xmm7, [rsp + 0xE8]
-        movdqu xmm8, [rsp + 0xF8]
-        movdqu xmm9, [rsp + 0x108]
-        movdqu xmm10, [rsp + 0x118]
-        movdqu xmm11, [rsp + 0x128]
-        movdqu xmm12, [rsp + 0x138]
-        movdqu xmm13, [rsp + 0x148]
-        movdqu xmm14, [rsp + 0x158]
-        movdqu xmm15, [rsp + 0x168]
-
         mov rax, [rsp + 0x00]
         mov rbx, [rsp + 0x08]
         mov rcx, [rsp + 0x10]
@@ -72,26 +37,336 @@ global_asm!(
         mov r14, [rsp + 0x68]
         mov r15, [rsp + 0x70]
 
-        add rsp, 0x178
+        add rsp, 0x78
     .endm
 
-    .global timer_interrupt_handler
-    .type timer_interrupt_handler, @function
-    timer_interrupt_handler:
+    // Saves the extended register state into the area the per-CPU block
+    // points at. There is none while a CPU runs its idle context.
+    .macro SAVE_FPU_STATE
+        mov rcx, gs:[{fpu_area}]
+        test rcx, rcx
+        jz 4f
+        mov eax, -1
+        mov edx, -1
+        cmp byte ptr [rip + {fpu_mode}], {mode_xsaveopt}
+        je 3f
+        cmp byte ptr [rip + {fpu_mode}], {mode_xsave}
+        je 2f
+        fxsave64 [rcx]
+        jmp 4f
+    2:
+        xsave64 [rcx]
+        jmp 4f
+    3:
+        xsaveopt64 [rcx]
+    4:
+    .endm
+
+    .macro RESTORE_FPU_STATE
+        mov rcx, gs:[{fpu_area}]
+        test rcx, rcx
+        jz 6f
+        mov eax, -1
+        mov edx, -1
+        cmp byte ptr [rip + {fpu_mode}], {mode_fxsave}
+        je 5f
+        xrstor64 [rcx]
+        jmp 6f
+    5:
+        fxrstor64 [rcx]
+    6:
+    .endm
+
+    // Shared timer entry. `source` tells the dispatcher which controller to
+    // acknowledge: 0 = PIT through the 8259 PIC, 1 = local APIC timer,
+    // 2 = none, a `yield_now` software interrupt.
+    .macro TIMER_ENTRY name, source
+    .global \name
+    .type \name, @function
+    \name:
+        // The kernel runs with its own GS base; swap it in when ring 3 was
+        // interrupted.
+        test qword ptr [rsp + 8], 3
+        jz 7f
+        swapgs
+    7:
         SAVE_CONTEXT
+        SAVE_FPU_STATE
 
         cld
         mov rdi, rsp
+        mov esi, \source
         and rsp, -16
-        sub rsp, 8
         call timer_interrupt_dispatch
         mov rsp, rax
 
+        // Now on the next task's stack: let the scheduler release the previous
+        // task so another CPU may resume it. Scratch registers are reloaded
+        // by RESTORE_CONTEXT.
+        mov rbx, rsp
+        and rsp, -16
+        call timer_interrupt_finish
+        mov rsp, rbx
+
+        RESTORE_FPU_STATE
+        RESTORE_CONTEXT
+        test qword ptr [rsp + 8], 3
+        jz 8f
+        swapgs
+    8:
+        iretq
+
+    .size \name, . - \name
+    .endm
+
+    TIMER_ENTRY timer_interrupt_handler, 0
+    TIMER_ENTRY lapic_timer_interrupt_handler, 1
+    TIMER_ENTRY yield_interrupt_handler, 2
+
+    // NMIs arrive on their own IST stack, possibly in the middle of another
+    // entry path, so `nmi_dispatch` fixes up the GS base itself and the x87
+    // and SSE registers are saved on the stack rather than in the task's area.
+    .global nmi_entry
+    .type nmi_entry, @function
+    nmi_entry:
+        SAVE_CONTEXT
+        mov rbx, rsp
+        and rsp, -16
+        sub rsp, 512
+        fxsave64 [rsp]
+
+        cld
+        mov rdi, rbx
+        call nmi_dispatch
+
+        fxrstor64 [rsp]
+        mov rsp, rbx
         RESTORE_CONTEXT
         iretq
+    .size nmi_entry, . - nmi_entry
+"#,
+    fpu_area = const crate::percpu::FPU_AREA_OFFSET,
+    fpu_mode = sym crate::fpu::MODE,
+    mode_fxsave = const crate::fpu::SaveMode::Fxsave as u8,
+    mode_xsave = const crate::fpu::SaveMode::Xsave as u8,
+    mode_xsaveopt = const crate::fpu::SaveMode::Xsaveopt as u8,
+);
+
+// System call entries. Both build the `SyscallFrame` expected by
+// `syscall_dispatch`: rax (the number) at the lowest address, then rdi, rsi,
+// rdx, r10, r8 and r9. Everything except rax is restored on return.
+global_asm!(
+    r#"
+    .macro PUSH_SYSCALL_FRAME
+        push r9
+        push r8
+        push r10
+        push rdx
+        push rsi
+        push rdi
+        push rax
+    .endm
+
+    .macro POP_SYSCALL_FRAME
+        add rsp, 8
+        pop rdi
+        pop rsi
+        pop rdx
+        pop r10
+        pop r8
+        pop r9
+    .endm
+
+    // SYSCALL leaves the user rip in rcx, rflags in r11 and rsp untouched.
+    // It is only ever executed in ring 3, so GS always needs swapping.
+    .global syscall_entry
+    .type syscall_entry, @function
+    syscall_entry:
+        swapgs
+        mov gs:[{user_rsp}], rsp
+        mov rsp, gs:[{kernel_stack}]
+        push qword ptr gs:[{user_rsp}]
+        push r11
+        push rcx
+        PUSH_SYSCALL_FRAME
+
+        cld
+        mov rdi, rsp
+        call syscall_dispatch
+
+        // On Intel CPUs SYSRET to a non-canonical rip faults in ring 0 with
+        // the user GS and rsp already loaded. Anything above the lower half
+        // returns through `iretq`, which faults on the kernel stack instead.
+        mov rdi, [rsp + 56]
+        shr rdi, 47
+        jnz 4f
+
+        POP_SYSCALL_FRAME
+        pop rcx
+        pop r11
+        pop rsp
+        swapgs
+        sysretq
+
+    4:
+        POP_SYSCALL_FRAME
+        pop rcx
+        pop r11
+        push {user_data}
+        push qword ptr [rsp + 8]
+        push r11
+        push {user_code}
+        push rcx
+        swapgs
+        iretq
+    .size syscall_entry, . - syscall_entry
+
+    // `int 0x80` gate, usable from ring 0 and ring 3.
+    .global syscall_int80_entry
+    .type syscall_int80_entry, @function
+    syscall_int80_entry:
+        test qword ptr [rsp + 8], 3
+        jz 2f
+        swapgs
+    2:
+        push r11
+        push rcx
+        PUSH_SYSCALL_FRAME
+
+        cld
+        mov rdi, rsp
+        call syscall_dispatch
 
-    .size timer_interrupt_handler, . - timer_interrupt_handler
-"#
+        POP_SYSCALL_FRAME
+        pop rcx
+        pop r11
+        test qword ptr [rsp + 8], 3
+        jz 3f
+        swapgs
+    3:
+        iretq
+    .size syscall_int80_entry, . - syscall_int80_entry
+"#,
+    kernel_stack = const crate::percpu::KERNEL_STACK_OFFSET,
+    user_rsp = const crate::percpu::USER_RSP_OFFSET,
+    user_data = const crate::gdt::USER_DATA_SELECTOR,
+    user_code = const crate::gdt::USER_CODE_SELECTOR,
+);
+
+// Application processor startup trampoline. `smp` copies the bytes between
+// `ap_trampoline_start` and `ap_trampoline_end` to a page below 1 MiB and
+// fills in `ap_trampoline_params` before sending the startup IPI. The code
+// only uses addresses relative to its runtime base (kept in ebx/rbx), so it
+// runs wherever it was copied.
+global_asm!(
+    r#"
+    .set AP_CODE32_SEL, 0x08
+    .set AP_DATA_SEL, 0x10
+    .set AP_CODE64_SEL, 0x18
+    .set AP_PARAM_CR3, ap_trampoline_params - ap_trampoline_start
+    .set AP_PARAM_STACK, ap_trampoline_params - ap_trampoline_start + 8
+    .set AP_PARAM_ENTRY, ap_trampoline_params - ap_trampoline_start + 16
+    .set AP_PARAM_CPU, ap_trampoline_params - ap_trampoline_start + 24
+
+    .global ap_trampoline_start
+    .global ap_trampoline_params
+    .global ap_trampoline_end
+
+    .code16
+    ap_trampoline_start:
+        cli
+        cld
+        movw %cs, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+        xorl %ebx, %ebx
+        movw %ax, %bx
+        shll $4, %ebx
+
+        // Patch the GDT base and the protected-mode far pointer.
+        leal (ap_gdt - ap_trampoline_start)(%ebx), %eax
+        movl %eax, (ap_gdtr - ap_trampoline_start + 2)
+        leal (ap_protected_mode - ap_trampoline_start)(%ebx), %eax
+        movl %eax, (ap_far32 - ap_trampoline_start)
+
+        lgdtl (ap_gdtr - ap_trampoline_start)
+        movl %cr0, %eax
+        orl $1, %eax
+        movl %eax, %cr0
+        ljmpl *(ap_far32 - ap_trampoline_start)
+
+    .code32
+    ap_protected_mode:
+        movw $AP_DATA_SEL, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+
+        // CR4: PAE | OSFXSR | OSXMMEXCPT, so SSE code works once in Rust.
+        movl $((1 << 5) | (1 << 9) | (1 << 10)), %eax
+        movl %eax, %cr4
+        movl AP_PARAM_CR3(%ebx), %eax
+        movl %eax, %cr3
+
+        // EFER.LME
+        movl $0xC0000080, %ecx
+        rdmsr
+        orl $(1 << 8), %eax
+        wrmsr
+
+        // CR0: PG | MP, clear EM.
+        movl %cr0, %eax
+        andl $~(1 << 2), %eax
+        orl $((1 << 31) | (1 << 1)), %eax
+        movl %eax, %cr0
+
+        leal (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+        movl %eax, (ap_far64 - ap_trampoline_start)(%ebx)
+        ljmpl *(ap_far64 - ap_trampoline_start)(%ebx)
+
+    .code64
+    ap_long_mode:
+        movw $AP_DATA_SEL, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+        movw %ax, %fs
+        movw %ax, %gs
+
+        movl %ebx, %ebx
+        movq AP_PARAM_STACK(%rbx), %rsp
+        movq AP_PARAM_CPU(%rbx), %rdi
+        movq AP_PARAM_ENTRY(%rbx), %rax
+        callq *%rax
+        ud2
+
+    .balign 8
+    ap_gdt:
+        .quad 0
+        .quad 0x00CF9A000000FFFF
+        .quad 0x00CF92000000FFFF
+        .quad 0x00AF9A000000FFFF
+    ap_gdt_end:
+    ap_gdtr:
+        .word ap_gdt_end - ap_gdt - 1
+        .long 0
+    ap_far32:
+        .long 0
+        .word AP_CODE32_SEL
+    ap_far64:
+        .long 0
+        .word AP_CODE64_SEL
+
+    .balign 8
+    ap_trampoline_params:
+        .quad 0
+        .quad 0
+        .quad 0
+        .quad 0
+    ap_trampoline_end:
+"#,
+    options(att_syntax)
 );
 
 #[cfg(target_arch = "x86_64")]
@@ -149,3 +424,92 @@ pub unsafe fn copy_sse2(src: *const u8, dst: *mut u8, len: usize) {
         }
     }
 }
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,avx2")]
+pub unsafe fn copy_avx2(src: *const u8, dst: *mut u8, len: usize) {
+    use core::arch::x86_64::*;
+    use core::ptr;
+
+    if len == 0 || src == dst {
+        return;
+    }
+
+    let src_addr = src as usize;
+    let dst_addr = dst as usize;
+    let overlap = match (src_addr.checked_add(len), dst_addr.checked_add(len)) {
+        (Some(src_end), Some(dst_end)) => src_addr < dst_end && dst_addr < src_end,
+        _ => true,
+    };
+    if overlap {
+        unsafe {
+            ptr::copy(src, dst, len);
+        }
+        return;
+    }
+
+    let mut i = 0usize;
+    let mut used_stream_store = false;
+
+    unsafe {
+        // Align destination to 32 bytes for streaming stores.
+        while i < len && ((dst.add(i) as usize) & 0x1F) != 0 {
+            ptr::write(dst.add(i), ptr::read(src.add(i)));
+            i += 1;
+        }
+
+        while i + 128 <= len {
+            let a = _mm256_loadu_si256(src.add(i) as *const __m256i);
+            let b = _mm256_loadu_si256(src.add(i + 32) as *const __m256i);
+            let c = _mm256_loadu_si256(src.add(i + 64) as *const __m256i);
+            let d = _mm256_loadu_si256(src.add(i + 96) as *const __m256i);
+
+            _mm256_stream_si256(dst.add(i) as *mut __m256i, a);
+            _mm256_stream_si256(dst.add(i + 32) as *mut __m256i, b);
+            _mm256_stream_si256(dst.add(i + 64) as *mut __m256i, c);
+            _mm256_stream_si256(dst.add(i + 96) as *mut __m256i, d);
+            i += 128;
+            used_stream_store = true;
+        }
+
+        if i < len {
+            ptr::copy_nonoverlapping(src.add(i), dst.add(i), len - i);
+        }
+        if used_stream_store {
+            _mm_sfence();
+        }
+    }
+}
+
+/// `rep movsb` copy, fastest on CPUs with enhanced `rep movsb` (ERMS).
+pub unsafe fn copy_erms(src: *const u8, dst: *mut u8, len: usize) {
+    use core::arch::asm;
+    use core::ptr;
+
+    if len == 0 || src == dst {
+        return;
+    }
+
+    let src_addr = src as usize;
+    let dst_addr = dst as usize;
+    let overlap = match (src_addr.checked_add(len), dst_addr.checked_add(len)) {
+        (Some(src_end), Some(dst_end)) => src_addr < dst_end && dst_addr < src_end,
+        _ => true,
+    };
+    if overlap {
+        unsafe {
+            ptr::copy(src, dst, len);
+        }
+        return;
+    }
+
+    unsafe {
+        asm!(
+            "rep movsb",
+            inout("rsi") src => _,
+            inout("rdi") dst => _,
+            inout("rcx") len => _,
+            options(nostack, preserves_flags)
+        );
+    }
+}
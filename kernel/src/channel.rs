@@ -0,0 +1,247 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::sync::WaitQueue;
+use crate::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a channel without receivers")
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on an empty channel without senders")
+    }
+}
+
+struct Channel<T> {
+    // Locked with interrupts disabled, since interrupt handlers send.
+    queue: spin::Mutex<VecDeque<T>>,
+    // `None` for unbounded channels.
+    capacity: Option<usize>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    // Receivers waiting for a message, and senders waiting for room.
+    receiving: WaitQueue,
+    sending: WaitQueue,
+}
+
+impl<T> Channel<T> {
+    fn try_push(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receivers.load(Ordering::Acquire) == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+        interrupts::without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            if self
+                .capacity
+                .is_some_and(|capacity| queue.len() >= capacity)
+            {
+                return Err(TrySendError::Full(value));
+            }
+            queue.push_back(value);
+            Ok(())
+        })?;
+        self.receiving.wake_one();
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = interrupts::without_interrupts(|| self.queue.lock().pop_front());
+        if value.is_some() && self.capacity.is_some() {
+            self.sending.wake_one();
+        }
+        value
+    }
+
+    fn try_pop(&self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+        if self.senders.load(Ordering::Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // The last sender may have sent just before it went away.
+        self.pop().ok_or(TryRecvError::Disconnected)
+    }
+}
+
+/// Sending half of a channel. Clone it for more producers.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Receiving half of a channel. Clone it for more consumers; each message
+/// goes to one of them.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: spin::Mutex::new(VecDeque::with_capacity(capacity.unwrap_or(0))),
+        capacity,
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        receiving: WaitQueue::named("channel recv"),
+        sending: WaitQueue::named("channel send"),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+/// A channel that holds any number of messages; `send` never blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+/// A channel that holds up to `capacity` messages; `send` blocks while it
+/// is full. The queue is allocated up front, so sending never allocates.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    if capacity == 0 {
+        panic!("channel capacity must be non-zero");
+    }
+    new_channel(Some(capacity))
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, waiting for room on a full bounded channel. Fails
+    /// once every receiver is gone, handing the value back.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        let mut result = Ok(());
+        self.channel.sending.wait_until(|| {
+            match self.channel.try_push(value.take().expect("value to send")) {
+                Ok(()) => true,
+                Err(TrySendError::Full(rejected)) => {
+                    value = Some(rejected);
+                    false
+                }
+                Err(TrySendError::Disconnected(rejected)) => {
+                    result = Err(SendError(rejected));
+                    true
+                }
+            }
+        });
+        result
+    }
+
+    /// Queues `value` if there is room, without blocking. Usable from
+    /// interrupt context; on a bounded channel it does not allocate either.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_push(value)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.receiving.wake_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for a message. Fails once the channel is empty and every
+    /// sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut result = Err(RecvError);
+        self.channel.receiving.wait_until(|| match self.try_recv() {
+            Ok(value) => {
+                result = Ok(value);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        });
+        result
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.channel.try_pop()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_deadline(Instant::now().saturating_add(timeout))
+    }
+
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        let mut result = Err(RecvTimeoutError::Disconnected);
+        self.channel
+            .receiving
+            .wait_until_deadline(deadline, || match self.try_recv() {
+                Ok(value) => {
+                    result = Ok(value);
+                    true
+                }
+                Err(TryRecvError::Empty) => false,
+                Err(TryRecvError::Disconnected) => true,
+            })
+            .map_err(|_| RecvTimeoutError::Timeout)?;
+        result
+    }
+
+    /// Messages as they arrive, until every sender is gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        core::iter::from_fn(|| self.recv().ok())
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.sending.wake_all();
+        }
+    }
+}
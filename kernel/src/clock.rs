@@ -0,0 +1,83 @@
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use crate::cpu::{self, Feature};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    RtcTicks = 0,
+    Hpet = 1,
+    InvariantTsc = 2,
+}
+
+impl ClockSource {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Hpet,
+            2 => Self::InvariantTsc,
+            _ => Self::RtcTicks,
+        }
+    }
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::RtcTicks as u8);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Busy-waits on the best hardware reference. Used for timer calibration and
+/// bring-up delays before the scheduler runs.
+pub fn busy_wait_us(microseconds: u64) {
+    if crate::hpet::is_enabled() {
+        crate::hpet::busy_wait_us(microseconds);
+    } else {
+        crate::pit::busy_wait_us(microseconds);
+    }
+}
+
+/// Picks the monotonic clock source. An invariant TSC is the cheapest to read,
+/// the HPET is next, and 1024 Hz RTC ticks are the last resort.
+pub fn init() {
+    let source = if cpu::has(Feature::InvariantTsc) && crate::tsc::frequency_hz() != 0 {
+        TSC_BASE.store(crate::tsc::read(), Ordering::Release);
+        ClockSource::InvariantTsc
+    } else if crate::hpet::is_enabled() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::RtcTicks
+    };
+    SOURCE.store(source as u8, Ordering::Release);
+}
+
+pub fn source() -> ClockSource {
+    ClockSource::from_u8(SOURCE.load(Ordering::Acquire))
+}
+
+/// Whether the clock only advances while interrupts are delivered.
+pub fn needs_interrupts() -> bool {
+    source() == ClockSource::RtcTicks
+}
+
+/// Nanoseconds between two distinct readings of the selected source.
+pub fn resolution_ns() -> u64 {
+    let hz = match source() {
+        ClockSource::InvariantTsc => crate::tsc::frequency_hz(),
+        ClockSource::Hpet => crate::hpet::frequency_hz(),
+        ClockSource::RtcTicks => crate::rtc::TICKS_PER_SEC,
+    };
+    1_000_000_000u64.div_ceil(hz.max(1))
+}
+
+/// Monotonic nanoseconds from the selected clock source.
+pub fn nanos() -> u64 {
+    match source() {
+        ClockSource::InvariantTsc => {
+            let cycles = crate::tsc::read().wrapping_sub(TSC_BASE.load(Ordering::Acquire));
+            let hz = crate::tsc::frequency_hz();
+            (cycles as u128 * 1_000_000_000 / hz as u128) as u64
+        }
+        ClockSource::Hpet => crate::hpet::nanos(),
+        ClockSource::RtcTicks => {
+            let ticks = crate::rtc::ticks();
+            (ticks as u128 * 1_000_000_000 / crate::rtc::TICKS_PER_SEC as u128) as u64
+        }
+    }
+}
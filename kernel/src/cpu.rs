@@ -0,0 +1,234 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+// CPUID leaf 1.
+const LEAF1_EDX_APIC: u32 = 1 << 9;
+const LEAF1_EDX_PAT: u32 = 1 << 16;
+const LEAF1_EDX_SSE2: u32 = 1 << 26;
+const LEAF1_ECX_MONITOR: u32 = 1 << 3;
+const LEAF1_ECX_TSC_DEADLINE: u32 = 1 << 24;
+const LEAF1_ECX_XSAVE: u32 = 1 << 26;
+const LEAF1_ECX_AVX: u32 = 1 << 28;
+// CPUID leaf 7, subleaf 0.
+const LEAF7_EBX_FSGSBASE: u32 = 1 << 0;
+const LEAF7_EBX_SMEP: u32 = 1 << 7;
+const LEAF7_EBX_AVX2: u32 = 1 << 5;
+const LEAF7_EBX_ERMS: u32 = 1 << 9;
+const LEAF7_EBX_SMAP: u32 = 1 << 20;
+const LEAF7_ECX_UMIP: u32 = 1 << 2;
+const LEAF7_EDX_FSRM: u32 = 1 << 4;
+// CPUID leaf 0xD.
+const LEAF_D_XCR0_AVX: u32 = 1 << 2;
+const LEAF_D1_EAX_XSAVEOPT: u32 = 1 << 0;
+// CPUID leaf 0xA.
+const CPUID_PERFMON_LEAF: u32 = 0xA;
+const LEAF_A_EBX_NO_CORE_CYCLES: u32 = 1 << 0;
+// Extended leaves.
+const EXT_MAX: u32 = 0x8000_0000;
+const EXT_FEATURES: u32 = 0x8000_0001;
+const EXT_ADVANCED_POWER: u32 = 0x8000_0007;
+const EXT1_EDX_NX: u32 = 1 << 20;
+const EXT7_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Sse2,
+    Apic,
+    Pat,
+    Nx,
+    TscDeadline,
+    InvariantTsc,
+    Xsave,
+    Xsaveopt,
+    /// Only reported when the AVX register state can be enabled in XCR0.
+    Avx,
+    Avx2,
+    /// Enhanced `rep movsb`.
+    Erms,
+    /// Fast short `rep movsb`.
+    Fsrm,
+    Fsgsbase,
+    Smep,
+    Smap,
+    Umip,
+    /// Architectural performance monitoring with a general-purpose counter
+    /// that can count unhalted core cycles.
+    PerfMon,
+    /// `monitor` and `mwait`.
+    Monitor,
+}
+
+const ALL_FEATURES: [Feature; 18] = [
+    Feature::Sse2,
+    Feature::Apic,
+    Feature::Pat,
+    Feature::Nx,
+    Feature::TscDeadline,
+    Feature::InvariantTsc,
+    Feature::Xsave,
+    Feature::Xsaveopt,
+    Feature::Avx,
+    Feature::Avx2,
+    Feature::Erms,
+    Feature::Fsrm,
+    Feature::Fsgsbase,
+    Feature::Smep,
+    Feature::Smap,
+    Feature::Umip,
+    Feature::PerfMon,
+    Feature::Monitor,
+];
+
+static FEATURES: AtomicU64 = AtomicU64::new(0);
+static PERFMON_VERSION: AtomicU8 = AtomicU8::new(0);
+static PERFMON_COUNTER_BITS: AtomicU8 = AtomicU8::new(0);
+
+/// Architectural performance monitoring details from CPUID leaf 0xA.
+#[derive(Clone, Copy, Debug)]
+pub struct PerfMon {
+    pub version: u8,
+    /// Width of the general-purpose counters.
+    pub counter_bits: u8,
+}
+
+fn detect() -> u64 {
+    let mut features = 0u64;
+    let mut set = |feature: Feature, present: bool| {
+        if present {
+            features |= 1 << feature as u8;
+        }
+    };
+
+    let leaf1 = __cpuid(1);
+    set(Feature::Sse2, leaf1.edx & LEAF1_EDX_SSE2 != 0);
+    set(Feature::Apic, leaf1.edx & LEAF1_EDX_APIC != 0);
+    set(Feature::Pat, leaf1.edx & LEAF1_EDX_PAT != 0);
+    set(Feature::Monitor, leaf1.ecx & LEAF1_ECX_MONITOR != 0);
+    set(
+        Feature::TscDeadline,
+        leaf1.ecx & LEAF1_ECX_TSC_DEADLINE != 0,
+    );
+
+    let max_leaf = __cpuid(0).eax;
+    let xsave = leaf1.ecx & LEAF1_ECX_XSAVE != 0 && max_leaf >= 0xD;
+    set(Feature::Xsave, xsave);
+    let avx_state = xsave && __cpuid_count(0xD, 0).eax & LEAF_D_XCR0_AVX != 0;
+    let avx = avx_state && leaf1.ecx & LEAF1_ECX_AVX != 0;
+    set(Feature::Avx, avx);
+    set(
+        Feature::Xsaveopt,
+        xsave && __cpuid_count(0xD, 1).eax & LEAF_D1_EAX_XSAVEOPT != 0,
+    );
+
+    if max_leaf >= 7 {
+        let leaf7 = __cpuid_count(7, 0);
+        set(Feature::Avx2, avx && leaf7.ebx & LEAF7_EBX_AVX2 != 0);
+        set(Feature::Erms, leaf7.ebx & LEAF7_EBX_ERMS != 0);
+        set(Feature::Fsrm, leaf7.edx & LEAF7_EDX_FSRM != 0);
+        set(Feature::Fsgsbase, leaf7.ebx & LEAF7_EBX_FSGSBASE != 0);
+        set(Feature::Smep, leaf7.ebx & LEAF7_EBX_SMEP != 0);
+        set(Feature::Smap, leaf7.ebx & LEAF7_EBX_SMAP != 0);
+        set(Feature::Umip, leaf7.ecx & LEAF7_ECX_UMIP != 0);
+    }
+
+    if max_leaf >= CPUID_PERFMON_LEAF {
+        let leaf_a = __cpuid(CPUID_PERFMON_LEAF);
+        let version = leaf_a.eax & 0xFF;
+        let counters = (leaf_a.eax >> 8) & 0xFF;
+        let width = (leaf_a.eax >> 16) & 0xFF;
+        let events = (leaf_a.eax >> 24) & 0xFF;
+        PERFMON_VERSION.store(version as u8, Ordering::Release);
+        PERFMON_COUNTER_BITS.store(width as u8, Ordering::Release);
+        set(
+            Feature::PerfMon,
+            version >= 1
+                && counters >= 1
+                && events >= 1
+                && leaf_a.ebx & LEAF_A_EBX_NO_CORE_CYCLES == 0,
+        );
+    }
+
+    let max_ext = __cpuid(EXT_MAX).eax;
+    if max_ext >= EXT_FEATURES {
+        set(Feature::Nx, __cpuid(EXT_FEATURES).edx & EXT1_EDX_NX != 0);
+    }
+    if max_ext >= EXT_ADVANCED_POWER {
+        set(
+            Feature::InvariantTsc,
+            __cpuid(EXT_ADVANCED_POWER).edx & EXT7_EDX_INVARIANT_TSC != 0,
+        );
+    }
+
+    features
+}
+
+/// Whether the CPU supports `feature`. Valid once [`init`] has run.
+pub fn has(feature: Feature) -> bool {
+    FEATURES.load(Ordering::Acquire) & (1 << feature as u8) != 0
+}
+
+/// Performance monitoring details, if [`Feature::PerfMon`] is present.
+pub fn perfmon() -> Option<PerfMon> {
+    has(Feature::PerfMon).then(|| PerfMon {
+        version: PERFMON_VERSION.load(Ordering::Acquire),
+        counter_bits: PERFMON_COUNTER_BITS.load(Ordering::Acquire),
+    })
+}
+
+/// Detected features, printable without the heap.
+#[derive(Clone, Copy)]
+pub struct Features;
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(ALL_FEATURES.into_iter().filter(|&feature| has(feature)))
+            .finish()
+    }
+}
+
+pub fn features() -> Features {
+    Features
+}
+
+/// Detects CPU features once, on the bootstrap processor.
+pub fn init() {
+    let features = detect();
+    FEATURES.store(features, Ordering::Release);
+
+    if !has(Feature::Sse2) {
+        panic!("the kernel requires SSE2");
+    }
+}
+
+/// Enables the optional control register features the CPU supports on the
+/// calling CPU. Runs once the kernel's own page tables are loaded, since
+/// SMEP and SMAP depend on every kernel mapping being a supervisor page.
+pub fn enable_features() {
+    unsafe {
+        Cr4::update(|flags| {
+            if has(Feature::Xsave) {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+            if has(Feature::Fsgsbase) {
+                flags.insert(Cr4Flags::FSGSBASE);
+            }
+            if has(Feature::Smep) {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            }
+            if has(Feature::Smap) {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+            }
+            if has(Feature::Umip) {
+                flags.insert(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION);
+            }
+        });
+        if has(Feature::Nx) {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use crate::debug;
+
+const MAX_FRAMES: usize = 16;
+// A saved frame pointer further up the stack than this is assumed corrupt.
+const MAX_FRAME_BYTES: u64 = 64 * 1024;
+
+/// Prints the return addresses found by following the frame pointer chain
+/// from `rbp`. Relies on the kernel being built with frame pointers, see
+/// `KERNEL_RUSTC_ARGS` in the Makefile; the walk stops at the first frame
+/// that does not look like one.
+pub fn print(rbp: u64) {
+    let mut rbp = rbp;
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) {
+            break;
+        }
+        let (next, return_addr) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_addr == 0 {
+            break;
+        }
+        debug::println!("  #{:<2} {:#018x}", depth, return_addr);
+        if next <= rbp || next - rbp > MAX_FRAME_BYTES {
+            break;
+        }
+        rbp = next;
+    }
+}
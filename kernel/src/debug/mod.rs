@@ -1,3 +1,4 @@
+pub mod backtrace;
 #[cfg(not(test))]
 pub mod panic;
 
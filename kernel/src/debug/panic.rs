@@ -18,6 +18,9 @@ fn panic(info: &PanicInfo<'_>) -> ! {
         debug::println!("location: <unknown>");
     }
 
+    // Only returns if the panic has to stop this CPU.
+    crate::multitask::exit_after_panic(info);
+
     loop {
         core::hint::spin_loop();
     }
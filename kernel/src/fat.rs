@@ -1,11 +1,12 @@
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cmp::min;
-use core::hint::spin_loop;
 
 use fatfs::{IoBase, IoError, Read, Seek, SeekFrom, Write};
 use x86_64::instructions::{interrupts, port::Port};
 
+use crate::time::{self, Duration};
+
 pub const FAT_SECTOR_SIZE: usize = 512;
 pub type IoResult<T> = core::result::Result<T, DiskIoError>;
 
@@ -154,7 +155,7 @@ impl AtaPioDevice {
     const CMD_FLUSH_CACHE: u8 = 0xE7;
     const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
 
-    const WAIT_SPINS: usize = 2_000_000;
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(2);
 
     pub fn primary_master() -> IoResult<Self> {
         Self::new(0x1F0, 0x3F6, AtaDrive::Master)
@@ -218,42 +219,36 @@ impl AtaPioDevice {
         let _ = self.read_alt_status();
     }
 
+    fn check_status(status: u8) -> IoResult<u8> {
+        if status & Self::STATUS_DF != 0 {
+            return Err(DiskIoError::DeviceFault);
+        }
+        if status & Self::STATUS_ERR != 0 {
+            return Err(DiskIoError::InvalidInput);
+        }
+        Ok(status)
+    }
+
     fn wait_not_busy(&self) -> IoResult<u8> {
-        for _ in 0..Self::WAIT_SPINS {
+        time::poll_until(Self::WAIT_TIMEOUT, || {
             let status = self.read_u8(Self::REG_STATUS_COMMAND);
-            if status & Self::STATUS_BSY == 0 {
-                if status & Self::STATUS_DF != 0 {
-                    return Err(DiskIoError::DeviceFault);
-                }
-                if status & Self::STATUS_ERR != 0 {
-                    return Err(DiskIoError::InvalidInput);
-                }
-                return Ok(status);
-            }
-            spin_loop();
-        }
-        Err(DiskIoError::Timeout)
+            (status & Self::STATUS_BSY == 0).then(|| Self::check_status(status))
+        })
+        .map_err(|_| DiskIoError::Timeout)?
     }
 
     fn wait_drq(&self) -> IoResult<()> {
-        for _ in 0..Self::WAIT_SPINS {
+        time::poll_until(Self::WAIT_TIMEOUT, || {
             let status = self.read_u8(Self::REG_STATUS_COMMAND);
             if status & Self::STATUS_BSY != 0 {
-                spin_loop();
-                continue;
-            }
-            if status & Self::STATUS_DF != 0 {
-                return Err(DiskIoError::DeviceFault);
-            }
-            if status & Self::STATUS_ERR != 0 {
-                return Err(DiskIoError::InvalidInput);
+                return None;
             }
-            if status & Self::STATUS_DRQ != 0 {
-                return Ok(());
+            match Self::check_status(status) {
+                Ok(status) if status & Self::STATUS_DRQ == 0 => None,
+                result => Some(result.map(|_| ())),
             }
-            spin_loop();
-        }
-        Err(DiskIoError::Timeout)
+        })
+        .map_err(|_| DiskIoError::Timeout)?
     }
 
     fn select_drive_base(&self) {
@@ -573,13 +568,41 @@ impl<D: BlockDevice> Seek for FatDisk<D> {
     }
 }
 
+/// Stamps created and modified files with the kernel's local wall-clock
+/// time, as FAT timestamps carry no time zone.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelTimeProvider;
+
+impl fatfs::TimeProvider for KernelTimeProvider {
+    fn get_current_date(&self) -> fatfs::Date {
+        fat_date(time::now_local())
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        let now = time::now_local();
+        let time = fatfs::Time::new(now.hour.into(), now.minute.into(), now.second.into(), 0);
+        fatfs::DateTime::new(fat_date(now), time)
+    }
+}
+
+// FAT dates cover 1980 to 2107; anything outside is clamped.
+fn fat_date(now: time::DateTime) -> fatfs::Date {
+    let (year, month, day) = match now.year {
+        ..1980 => (1980, 1, 1),
+        2108.. => (2107, 12, 31),
+        year => (year, now.month, now.day),
+    };
+    fatfs::Date::new(year, month.into(), day.into())
+}
+
 /// Minimal FAT usage example with an explicit device:
 /// mount -> create file -> write bytes -> unmount.
 pub fn mount_and_create_hello_file_with_device<D: BlockDevice>(
     dev: D,
 ) -> core::result::Result<(), fatfs::Error<DiskIoError>> {
     let disk = FatDisk::new(dev);
-    let fs = fatfs::FileSystem::new(disk, fatfs::FsOptions::new())?;
+    let options = fatfs::FsOptions::new().time_provider(KernelTimeProvider);
+    let fs = fatfs::FileSystem::new(disk, options)?;
     {
         let root = fs.root_dir();
         let mut file = root.create_file("HELLO.TXT")?;
@@ -0,0 +1,145 @@
+use alloc::alloc::{Layout, alloc_zeroed, handle_alloc_error};
+use core::arch::x86_64::__cpuid_count;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::cpu::{self, Feature};
+
+const FXSAVE_AREA_BYTES: usize = 512;
+const XSAVE_HEADER_BYTES: usize = 64;
+const AREA_ALIGN: usize = 64;
+
+// Default control words, as after `fninit`.
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+const CPUID_XSAVE_LEAF: u32 = 0xD;
+
+// Must match the mode checks in `SAVE_FPU_STATE` / `RESTORE_FPU_STATE` in asmtools.rs.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveMode {
+    Fxsave = 0,
+    Xsave = 1,
+    Xsaveopt = 2,
+}
+
+impl SaveMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Xsave,
+            2 => Self::Xsaveopt,
+            _ => Self::Fxsave,
+        }
+    }
+}
+
+pub static MODE: AtomicU8 = AtomicU8::new(SaveMode::Fxsave as u8);
+static XCR0: AtomicU64 = AtomicU64::new(0);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_BYTES);
+
+/// State components to enable: x87, SSE, and AVX / AVX-512 when present.
+fn supported_components() -> XCr0Flags {
+    let info = __cpuid_count(CPUID_XSAVE_LEAF, 0);
+    let available = XCr0Flags::from_bits_truncate(info.eax as u64 | (info.edx as u64) << 32);
+
+    let mut flags = XCr0Flags::X87 | XCr0Flags::SSE;
+    if available.contains(XCr0Flags::AVX) {
+        flags |= XCr0Flags::AVX;
+        let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        if available.contains(avx512) {
+            flags |= avx512;
+        }
+    }
+    flags
+}
+
+fn enable_local(xcr0: Option<XCr0Flags>) {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        // CR4.OSXSAVE is set by `cpu::enable_features` when XSAVE is present.
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        if let Some(xcr0) = xcr0 {
+            XCr0::write(xcr0);
+        }
+    }
+}
+
+/// Enables FXSAVE, or XSAVE with every supported state component, and sizes
+/// the per-task save area from CPUID leaf 0xD.
+///
+/// Registers are saved eagerly on every switch: kernel code itself uses SSE,
+/// so trapping the first use with CR0.TS would fire on every task. XSAVEOPT
+/// skips components a task has not modified since they were restored.
+pub fn init() {
+    if !cpu::has(Feature::Xsave) {
+        enable_local(None);
+        return;
+    }
+
+    let xcr0 = supported_components();
+    enable_local(Some(xcr0));
+    XCR0.store(xcr0.bits(), Ordering::Release);
+
+    // EBX is the area size for the components enabled in XCR0.
+    let size = __cpuid_count(CPUID_XSAVE_LEAF, 0).ebx as usize;
+    AREA_SIZE.store(
+        size.max(FXSAVE_AREA_BYTES + XSAVE_HEADER_BYTES),
+        Ordering::Release,
+    );
+
+    let mode = if cpu::has(Feature::Xsaveopt) {
+        SaveMode::Xsaveopt
+    } else {
+        SaveMode::Xsave
+    };
+    MODE.store(mode as u8, Ordering::Release);
+}
+
+/// Applies the bootstrap processor's configuration to an application processor.
+pub fn init_ap() {
+    let xcr0 = match mode() {
+        SaveMode::Fxsave => None,
+        SaveMode::Xsave | SaveMode::Xsaveopt => {
+            Some(XCr0Flags::from_bits_truncate(XCR0.load(Ordering::Acquire)))
+        }
+    };
+    enable_local(xcr0);
+}
+
+pub fn mode() -> SaveMode {
+    SaveMode::from_u8(MODE.load(Ordering::Acquire))
+}
+
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Acquire)
+}
+
+/// Allocates a save area holding the initial register state.
+pub fn alloc_area() -> usize {
+    let layout = Layout::from_size_align(area_size(), AREA_ALIGN).unwrap();
+    let area = unsafe { alloc_zeroed(layout) };
+    if area.is_null() {
+        handle_alloc_error(layout);
+    }
+    reset_area(area as usize);
+    area as usize
+}
+
+/// Resets a save area to the initial register state. An all-zero XSAVE
+/// header puts every component into its init state on restore, but MXCSR is
+/// still loaded from the legacy region, as is everything else by FXRSTOR.
+pub fn reset_area(area: usize) {
+    unsafe {
+        ptr::write_bytes(area as *mut u8, 0, area_size());
+        ptr::write((area + FCW_OFFSET) as *mut u16, DEFAULT_FCW);
+        ptr::write((area + MXCSR_OFFSET) as *mut u32, DEFAULT_MXCSR);
+    }
+}
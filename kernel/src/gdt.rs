@@ -1,41 +1,139 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
 use lazy_static::lazy_static;
+use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
 
-struct Selectors {
-    kernel_code: SegmentSelector,
-    kernel_data: SegmentSelector,
+use crate::percpu::percpu;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+const IST_STACK_SIZE: usize = 16 * 1024;
+// Ring 3 selectors, fixed by the order `build_tables` appends descriptors in,
+// for the assembly that builds an `iretq` frame.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
 }
 
+static mut BSP_DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut BSP_NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+// Written in place: RSP0 changes with every switch to a task.
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kernel_code = gdt.append(Descriptor::kernel_code_segment());
-        let kernel_data = gdt.append(Descriptor::kernel_data_segment());
-
-        // Keep user segments allocated for future ring3 support.
-        let _user_code = gdt.append(Descriptor::user_code_segment());
-        let _user_data = gdt.append(Descriptor::user_data_segment());
-
-        (
-            gdt,
-            Selectors {
-                kernel_code,
-                kernel_data,
-            },
-        )
+    static ref BSP_TABLES: CpuTables = {
+        let double_fault_stack = addr_of_mut!(BSP_DOUBLE_FAULT_STACK) as u64;
+        let nmi_stack = addr_of_mut!(BSP_NMI_STACK) as u64;
+        unsafe {
+            let tss = &mut *addr_of_mut!(BSP_TSS);
+            set_ist_stack(tss, DOUBLE_FAULT_IST_INDEX, double_fault_stack);
+            set_ist_stack(tss, NMI_IST_INDEX, nmi_stack);
+            build_tables(&*addr_of!(BSP_TSS))
+        }
     };
 }
 
-pub fn init() {
+percpu! {
+    static TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(core::ptr::null_mut());
+}
+
+fn set_ist_stack(tss: &mut TaskStateSegment, index: u16, stack_base: u64) {
+    let stack_top = stack_base + IST_STACK_SIZE as u64;
+    tss.interrupt_stack_table[index as usize] = VirtAddr::new(stack_top & !0xF);
+}
+
+fn build_tables(tss: &'static TaskStateSegment) -> CpuTables {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+
+    // SYSRET loads SS from STAR+8 and CS from STAR+16, so user data must
+    // directly precede user code.
+    let user_data = gdt.append(Descriptor::user_data_segment());
+    let user_code = gdt.append(Descriptor::user_code_segment());
+    if user_data.0 != USER_DATA_SELECTOR || user_code.0 != USER_CODE_SELECTOR {
+        panic!("user selectors do not match USER_DATA_SELECTOR/USER_CODE_SELECTOR");
+    }
+
+    let tss = gdt.append(Descriptor::tss_segment(tss));
+
+    CpuTables {
+        gdt,
+        selectors: Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    }
+}
+
+fn load(tables: &'static CpuTables) {
     use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    tables.gdt.load();
+    unsafe {
+        CS::set_reg(tables.selectors.kernel_code);
+        DS::set_reg(tables.selectors.kernel_data);
+        ES::set_reg(tables.selectors.kernel_data);
+        FS::set_reg(tables.selectors.kernel_data);
+        // Clears the GS base, so the per-CPU block is installed afterwards.
+        GS::set_reg(tables.selectors.kernel_data);
+        SS::set_reg(tables.selectors.kernel_data);
+        load_tss(tables.selectors.tss);
+    }
+}
+
+pub fn init() {
+    load(&BSP_TABLES);
+    TSS.get_for(0)
+        .store(addr_of_mut!(BSP_TSS), Ordering::Release);
+}
 
-    GDT.0.load();
+/// Loads a GDT and TSS owned by the calling application processor.
+/// Needs the heap, which the bootstrap processor's tables do not.
+pub fn init_ap(cpu_index: usize) {
+    let double_fault_stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+    let nmi_stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+    let tss = Box::into_raw(Box::new(TaskStateSegment::new()));
     unsafe {
-        CS::set_reg(GDT.1.kernel_code);
-        DS::set_reg(GDT.1.kernel_data);
-        ES::set_reg(GDT.1.kernel_data);
-        FS::set_reg(GDT.1.kernel_data);
-        GS::set_reg(GDT.1.kernel_data);
-        SS::set_reg(GDT.1.kernel_data);
+        set_ist_stack(
+            &mut *tss,
+            DOUBLE_FAULT_IST_INDEX,
+            double_fault_stack.as_ptr() as u64,
+        );
+        set_ist_stack(&mut *tss, NMI_IST_INDEX, nmi_stack.as_ptr() as u64);
+        load(Box::leak(Box::new(build_tables(&*tss))));
+    }
+    TSS.get_for(cpu_index).store(tss, Ordering::Release);
+}
+
+/// Segment selectors; the layout is the same on every CPU.
+pub fn selectors() -> &'static Selectors {
+    &BSP_TABLES.selectors
+}
+
+/// Sets the stack the CPU switches to on an interrupt from ring 3.
+pub fn set_kernel_stack(stack_top: u64) {
+    let tss = TSS.get().load(Ordering::Acquire);
+    if let Some(tss) = unsafe { tss.as_mut() } {
+        tss.privilege_stack_table[0] = VirtAddr::new(stack_top);
     }
 }
@@ -2,13 +2,13 @@ use core::ptr;
 
 use embedded_graphics::pixelcolor::Rgb888;
 use embedded_graphics::prelude::RgbColor;
-use spin::Mutex;
 use x86_64::instructions::interrupts;
 
 use crate::paging;
+use crate::sync::Mutex;
 
 pub const BOOT_INFO_MAGIC: u64 = 0x5255_5354_4F53_4749; // "RUSTOSGI"
-pub const BOOT_INFO_VERSION: u32 = 1;
+pub const BOOT_INFO_VERSION: u32 = 3;
 const HUGE_2MIB: u64 = 2 * 1024 * 1024;
 
 pub static GOP_SCREEN: Mutex<Framebuffer> = Mutex::new(Framebuffer {
@@ -21,7 +21,9 @@ pub static GOP_SCREEN: Mutex<Framebuffer> = Mutex::new(Framebuffer {
     bpp: 4,
     format: BootPixelFormat::Unknown,
     use_double_buffer: false,
-});
+})
+.with_priority_inheritance()
+.with_debug();
 
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -54,6 +56,8 @@ pub struct BootInfo {
     pub version: u32,
     pub _reserved0: u32,
     pub framebuffer: FramebufferInfo,
+    pub acpi_rsdp_addr: u64,
+    pub ap_trampoline_addr: u64,
 }
 
 pub struct Framebuffer {
@@ -250,9 +254,20 @@ impl Framebuffer {
     }
 
     pub fn refresh(&self) {
-        if self.use_double_buffer {
-            unsafe {
-                crate::asmtools::copy_sse2(self.back_base, self.front_base, self.size);
+        if !self.use_double_buffer {
+            return;
+        }
+
+        use crate::asmtools::{copy_avx2, copy_erms, copy_sse2};
+        use crate::cpu::{self, Feature};
+
+        unsafe {
+            if cpu::has(Feature::Avx2) {
+                copy_avx2(self.back_base, self.front_base, self.size);
+            } else if cpu::has(Feature::Erms) {
+                copy_erms(self.back_base, self.front_base, self.size);
+            } else {
+                copy_sse2(self.back_base, self.front_base, self.size);
             }
         }
     }
@@ -332,7 +347,7 @@ fn can_use_double_buffer(front_addr: u64, back_addr: u64, size: usize, back_size
     back_start >= front_end || front_start >= back_end
 }
 
-fn boot_info_from_ptr(boot_info_ptr: *const BootInfo) -> &'static BootInfo {
+pub fn boot_info_from_ptr(boot_info_ptr: *const BootInfo) -> &'static BootInfo {
     if boot_info_ptr.is_null() {
         panic!("boot info pointer is null");
     }
@@ -349,6 +364,11 @@ fn boot_info_from_ptr(boot_info_ptr: *const BootInfo) -> &'static BootInfo {
 }
 
 fn mark_framebuffer_write_combine(info: FramebufferInfo) {
+    // Without PAT the selector bit is reserved and must stay clear.
+    if !crate::cpu::has(crate::cpu::Feature::Pat) {
+        return;
+    }
+
     let end_addr = info
         .addr
         .checked_add(info.size.saturating_sub(1))
@@ -1,10 +1,11 @@
 use buddy_system_allocator::LockedHeap;
-use core::alloc::Layout;
+use core::alloc::{GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
 use spin::Once;
+use x86_64::instructions::interrupts;
 
 const HEAP_ORDER: usize = 32;
-const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB, task stacks included
 
 #[repr(align(4096))]
 struct HeapBytes([u8; HEAP_SIZE]);
@@ -13,11 +14,26 @@ struct KernelHeapMemory(UnsafeCell<HeapBytes>);
 
 unsafe impl Sync for KernelHeapMemory {}
 
+/// The heap's spin lock is only taken with interrupts disabled, so code
+/// that allocates while holding an interrupt-masked lock such as the
+/// scheduler's cannot deadlock with an interrupted allocation on another CPU.
+struct KernelHeap(LockedHeap<HEAP_ORDER>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
+
 static HEAP_MEMORY: KernelHeapMemory = KernelHeapMemory(UnsafeCell::new(HeapBytes([0; HEAP_SIZE])));
 static HEAP_INIT: Once<()> = Once::new();
 
 #[global_allocator]
-static HEAP: LockedHeap<HEAP_ORDER> = LockedHeap::<HEAP_ORDER>::new();
+static HEAP: KernelHeap = KernelHeap(LockedHeap::<HEAP_ORDER>::new());
 
 #[inline(always)]
 fn heap_start() -> usize {
@@ -26,7 +42,7 @@ fn heap_start() -> usize {
 
 pub fn init_heap() {
     HEAP_INIT.call_once(|| unsafe {
-        HEAP.lock().init(heap_start(), HEAP_SIZE);
+        HEAP.0.lock().init(heap_start(), HEAP_SIZE);
     });
 }
 
@@ -0,0 +1,322 @@
+use core::hint::spin_loop;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::acpi::{self, GenericAddress, SdtHeader};
+
+const HPET_MMIO_SIZE: u64 = 0x400;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+// The specification caps the tick period at 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_INTERRUPT_STATUS: usize = 0x020;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+const REG_TIMER_BASE: usize = 0x100;
+const REG_TIMER_STRIDE: usize = 0x20;
+const TIMER_CONFIG: usize = 0x00;
+const TIMER_COMPARATOR: usize = 0x08;
+const TIMER_FSB_ROUTE: usize = 0x10;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_TIMER_COUNT_SHIFT: u64 = 8;
+const CAP_TIMER_COUNT_MASK: u64 = 0x1F;
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_FORCE_32BIT: u64 = 1 << 8;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAP: u64 = 1 << 15;
+
+// FSB delivery writes a message like an MSI: the data in the low half of
+// the route register, the address in the high half.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_DESTINATION_SHIFT: u64 = 12;
+const MSI_DELIVERY_NMI: u64 = 0b100 << 8;
+const NO_COMPARATOR: usize = usize::MAX;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COMPARATORS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+// Software-extended counter for HPETs that only implement 32 bits.
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0);
+// Comparator delivering NMIs, its period and next expiry in counter ticks.
+static NMI_COMPARATOR: AtomicUsize = AtomicUsize::new(NO_COMPARATOR);
+static NMI_PERIOD_TICKS: AtomicU64 = AtomicU64::new(0);
+static NMI_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn read(reg: usize) -> u64 {
+    let base = BASE.load(Ordering::Acquire) as usize;
+    unsafe { ptr::read_volatile((base + reg) as *const u64) }
+}
+
+fn write(reg: usize, value: u64) {
+    let base = BASE.load(Ordering::Acquire) as usize;
+    unsafe { ptr::write_volatile((base + reg) as *mut u64, value) }
+}
+
+fn timer_reg(index: usize, reg: usize) -> usize {
+    REG_TIMER_BASE + index * REG_TIMER_STRIDE + reg
+}
+
+/// Locates the HPET through its ACPI table, maps its registers and starts the
+/// main counter. Returns `false` if the platform has no usable HPET.
+pub fn init() -> bool {
+    let Some(table_addr) = acpi::find_table(b"HPET") else {
+        return false;
+    };
+    let table = unsafe { ptr::read_unaligned(table_addr as *const HpetTable) };
+    if (table.header.length as usize) < mem::size_of::<HpetTable>() {
+        return false;
+    }
+
+    let address = table.base_address;
+    if address.address_space != acpi::ADDRESS_SPACE_SYSTEM_MEMORY || address.address == 0 {
+        return false;
+    }
+
+    interrupts::without_interrupts(|| {
+        crate::paging::map_mmio(address.address, HPET_MMIO_SIZE);
+        BASE.store(address.address, Ordering::Release);
+
+        let caps = read(REG_CAPABILITIES);
+        let period_fs = caps >> CAP_PERIOD_SHIFT;
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            BASE.store(0, Ordering::Release);
+            return false;
+        }
+
+        PERIOD_FS.store(period_fs, Ordering::Release);
+        COUNTER_64BIT.store(caps & CAP_COUNTER_64BIT != 0, Ordering::Release);
+        COMPARATORS.store(
+            ((caps >> CAP_TIMER_COUNT_SHIFT) & CAP_TIMER_COUNT_MASK) + 1,
+            Ordering::Release,
+        );
+
+        // Halt, reset and restart the main counter with legacy routing off so
+        // PIT and RTC interrupts keep working.
+        let config = read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+        write(REG_CONFIG, config);
+        for index in 0..comparator_count() {
+            let timer_config = read(timer_reg(index, TIMER_CONFIG));
+            write(
+                timer_reg(index, TIMER_CONFIG),
+                timer_config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+            );
+        }
+        write(REG_MAIN_COUNTER, 0);
+        EXTENDED_COUNTER.store(0, Ordering::Release);
+        write(REG_CONFIG, config | CONFIG_ENABLE);
+        true
+    })
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+pub fn frequency_hz() -> u64 {
+    match PERIOD_FS.load(Ordering::Acquire) {
+        0 => 0,
+        period_fs => 1_000_000_000_000_000 / period_fs,
+    }
+}
+
+/// Raw main counter value, extended to 64 bits when the hardware is narrower.
+///
+/// A 32-bit counter must be read at least once per wrap period (minutes at
+/// typical HPET rates); the scheduler tick takes care of that.
+pub fn counter() -> u64 {
+    let raw = read(REG_MAIN_COUNTER);
+    if COUNTER_64BIT.load(Ordering::Acquire) {
+        return raw;
+    }
+
+    let low = raw & 0xFFFF_FFFF;
+    let extend = |prev: u64| {
+        let mut next = (prev & !0xFFFF_FFFF) | low;
+        if next < prev {
+            next += 1 << 32;
+        }
+        next
+    };
+    let prev = EXTENDED_COUNTER
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |prev| {
+            Some(extend(prev))
+        })
+        .unwrap_or_else(|prev| prev);
+    extend(prev)
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let period_fs = PERIOD_FS.load(Ordering::Acquire) as u128;
+    (ticks as u128 * period_fs / FEMTOS_PER_NANO as u128) as u64
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    let period_fs = PERIOD_FS.load(Ordering::Acquire) as u128;
+    if period_fs == 0 {
+        return 0;
+    }
+    (nanos as u128 * FEMTOS_PER_NANO as u128).div_ceil(period_fs) as u64
+}
+
+/// Monotonic nanoseconds since [`init`].
+pub fn nanos() -> u64 {
+    if !is_enabled() {
+        return 0;
+    }
+    ticks_to_nanos(counter())
+}
+
+pub fn busy_wait_us(microseconds: u64) {
+    if !is_enabled() {
+        panic!("HPET is not initialized");
+    }
+
+    let deadline = counter().saturating_add(nanos_to_ticks(microseconds.saturating_mul(1000)));
+    while counter() < deadline {
+        spin_loop();
+    }
+}
+
+pub fn comparator_count() -> usize {
+    COMPARATORS.load(Ordering::Acquire) as usize
+}
+
+fn check_comparator(index: usize) {
+    if !is_enabled() {
+        panic!("HPET is not initialized");
+    }
+    if index >= comparator_count() {
+        panic!("HPET comparator index out of range");
+    }
+}
+
+/// Arms comparator `index` to fire once when [`nanos`] reaches `deadline_ns`.
+///
+/// Comparators are programmed level-triggered with interrupt delivery off, so
+/// expiry is observed by polling [`comparator_fired`]. Delivering them as
+/// interrupts needs an I/O APIC route, which the kernel does not program yet.
+#[allow(dead_code)]
+pub fn arm_oneshot(index: usize, deadline_ns: u64) {
+    check_comparator(index);
+
+    interrupts::without_interrupts(|| {
+        let mut deadline = nanos_to_ticks(deadline_ns);
+        let mut config = read(timer_reg(index, TIMER_CONFIG));
+        config &= !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC);
+        config |= TIMER_LEVEL_TRIGGERED;
+        if !COUNTER_64BIT.load(Ordering::Acquire) {
+            config |= TIMER_FORCE_32BIT;
+            deadline &= 0xFFFF_FFFF;
+        }
+
+        write(timer_reg(index, TIMER_CONFIG), config);
+        write(REG_INTERRUPT_STATUS, 1 << index);
+        write(timer_reg(index, TIMER_COMPARATOR), deadline);
+    });
+}
+
+#[allow(dead_code)]
+pub fn comparator_fired(index: usize) -> bool {
+    check_comparator(index);
+    read(REG_INTERRUPT_STATUS) & (1 << index) != 0
+}
+
+#[allow(dead_code)]
+pub fn disarm(index: usize) {
+    check_comparator(index);
+
+    interrupts::without_interrupts(|| {
+        let config = read(timer_reg(index, TIMER_CONFIG));
+        write(
+            timer_reg(index, TIMER_CONFIG),
+            config & !(TIMER_INTERRUPT_ENABLE | TIMER_LEVEL_TRIGGERED),
+        );
+        write(REG_INTERRUPT_STATUS, 1 << index);
+    });
+}
+
+/// Delivers an NMI to the local APIC `apic_id` every `period_ns`, through a
+/// comparator that supports FSB delivery, which needs no I/O APIC. Returns
+/// false if none does. The NMI handler tells these NMIs apart with
+/// [`nmi_expired`], which also re-arms the comparator.
+pub fn start_nmi(apic_id: u32, period_ns: u64) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let Some(index) = (0..comparator_count())
+        .rev()
+        .find(|&index| read(timer_reg(index, TIMER_CONFIG)) & TIMER_FSB_CAP != 0)
+    else {
+        return false;
+    };
+
+    interrupts::without_interrupts(|| {
+        let address = MSI_ADDRESS_BASE | (apic_id as u64) << MSI_DESTINATION_SHIFT;
+        write(
+            timer_reg(index, TIMER_FSB_ROUTE),
+            address << 32 | MSI_DELIVERY_NMI,
+        );
+        let mut config = read(timer_reg(index, TIMER_CONFIG));
+        config &= !(TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED);
+        config |= TIMER_FSB_ENABLE | TIMER_INTERRUPT_ENABLE;
+        if !COUNTER_64BIT.load(Ordering::Acquire) {
+            config |= TIMER_FORCE_32BIT;
+        }
+        NMI_PERIOD_TICKS.store(nanos_to_ticks(period_ns).max(1), Ordering::Release);
+        NMI_COMPARATOR.store(index, Ordering::Release);
+        write(timer_reg(index, TIMER_CONFIG), config);
+        arm_nmi(index, counter());
+    });
+    true
+}
+
+/// Whether the NMI comparator has expired, so the NMI being handled is
+/// probably its own. Re-arms it for the next period if so.
+pub fn nmi_expired() -> bool {
+    let index = NMI_COMPARATOR.load(Ordering::Acquire);
+    if index == NO_COMPARATOR {
+        return false;
+    }
+    let now = counter();
+    if now < NMI_DEADLINE.load(Ordering::Acquire) {
+        return false;
+    }
+    arm_nmi(index, now);
+    true
+}
+
+// One-shot rather than periodic, which not every FSB-capable comparator
+// supports; the comparator matches the low bits of the counter only.
+fn arm_nmi(index: usize, now: u64) {
+    let deadline = now.saturating_add(NMI_PERIOD_TICKS.load(Ordering::Acquire));
+    NMI_DEADLINE.store(deadline, Ordering::Release);
+    let value = if COUNTER_64BIT.load(Ordering::Acquire) {
+        deadline
+    } else {
+        deadline & 0xFFFF_FFFF
+    };
+    write(timer_reg(index, TIMER_COMPARATOR), value);
+}
@@ -0,0 +1,158 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering, fence};
+use x86_64::instructions::interrupts;
+
+use crate::cpu::Feature;
+use crate::percpu::{self, PerCpu, percpu};
+use crate::time::{Duration, Instant};
+
+// Longest the tick stays stopped when no timer is due, which bounds how late
+// a CPU notices work that nobody woke it for.
+const MAX_TICKLESS: Duration = Duration::from_secs(1);
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+percpu! {
+    // Set while the CPU halts with its periodic tick replaced by a one-shot.
+    static TICKLESS: AtomicBool = AtomicBool::new(false);
+    static TICKLESS_SINCE_NS: AtomicU64 = AtomicU64::new(0);
+    // Watched by `mwait`; `kick` writes it to wake the CPU without an IPI.
+    static WAKE_LINE: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Whether idle CPUs stop their tick. Needs the local APIC timer; the PIT
+/// fallback tick is shared and keeps running.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) && crate::lapic::is_enabled()
+}
+
+#[allow(dead_code)]
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether CPU `cpu_index` is halted with its tick stopped.
+pub fn is_tickless(cpu_index: usize) -> bool {
+    TICKLESS.get_for(cpu_index).load(Ordering::Acquire)
+}
+
+/// Halts the calling CPU until there is something to do. Runs as the idle
+/// context of every CPU, which the scheduler only switches to when no task
+/// is ready; the time spent in it is counted as idle.
+pub fn run() -> ! {
+    loop {
+        interrupts::disable();
+        // Armed before looking for work, so a `kick` that comes after the
+        // check still ends `mwait`.
+        arm_monitor();
+        if is_enabled() {
+            stop_tick();
+        }
+        if !crate::multitask::tasks_waiting() {
+            halt();
+        }
+        exit_tickless();
+        interrupts::enable();
+        // A task queued by a `kick` runs now rather than at the next tick.
+        if crate::multitask::tasks_waiting() {
+            crate::multitask::yield_now();
+        }
+    }
+}
+
+fn arm_monitor() {
+    if crate::cpu::has(Feature::Monitor) {
+        let line = WAKE_LINE.get() as *const AtomicU64;
+        unsafe {
+            asm!("monitor", in("rax") line, in("ecx") 0, in("edx") 0, options(nostack));
+        }
+    }
+}
+
+/// Waits for an interrupt, or with `mwait` also for a `kick` since
+/// [`arm_monitor`]. Called and returns with interrupts disabled; a pending
+/// interrupt is taken once they are enabled again.
+fn halt() {
+    if !crate::cpu::has(Feature::Monitor) {
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        return;
+    }
+    unsafe {
+        // Bit 0 of ECX ends the wait on an interrupt although they are masked.
+        asm!("mwait", in("eax") 0, in("ecx") 1, options(nostack));
+    }
+}
+
+/// Replaces the periodic tick with a one-shot timer for the next timer wheel
+/// or sleeper deadline, unless a task is waiting for a CPU. Interrupts are
+/// disabled.
+fn stop_tick() {
+    let tickless = TICKLESS.get();
+    // Published before looking at the run queues; `kick` looks at the flag
+    // after queueing, so one of the two sides sees the other.
+    tickless.store(true, Ordering::SeqCst);
+    if crate::multitask::tasks_waiting() {
+        tickless.store(false, Ordering::SeqCst);
+        return;
+    }
+
+    let now = Instant::now();
+    let deadline = match (
+        crate::timer::next_deadline(),
+        crate::multitask::next_wakeup(),
+    ) {
+        (Some(timer), Some(sleeper)) => Some(timer.min(sleeper)),
+        (timer, sleeper) => timer.or(sleeper),
+    };
+    let wait = deadline
+        .map_or(MAX_TICKLESS, |deadline| deadline.duration_since(now))
+        .min(MAX_TICKLESS);
+    let wait_us = wait.as_micros() as u64;
+    if wait_us <= crate::multitask::tick_interval_us() {
+        tickless.store(false, Ordering::SeqCst);
+        return;
+    }
+
+    TICKLESS_SINCE_NS
+        .get()
+        .store(now.as_nanos(), Ordering::Relaxed);
+    crate::lapic::arm_oneshot(wait_us);
+    percpu::current()
+        .stats
+        .tickless_entries
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Restarts the periodic tick if the calling CPU stopped it. Called with
+/// interrupts disabled by whatever runs first after the CPU wakes up: the
+/// idle loop, or the timer interrupt that may switch to a task instead.
+pub fn exit_tickless() {
+    if !TICKLESS.get().swap(false, Ordering::SeqCst) {
+        return;
+    }
+    crate::lapic::start_timer(
+        crate::lapic::preferred_timer_mode(),
+        crate::multitask::tick_interval_us(),
+    );
+
+    let since = TICKLESS_SINCE_NS.get().load(Ordering::Relaxed);
+    let elapsed = crate::clock::nanos().saturating_sub(since);
+    percpu::current()
+        .stats
+        .tickless_ns
+        .fetch_add(elapsed, Ordering::Relaxed);
+    crate::watchdog::touch();
+}
+
+/// Wakes `cpu` if it halts with its tick stopped, so it picks up a task just
+/// queued for it.
+pub fn kick(cpu: &PerCpu) {
+    fence(Ordering::SeqCst);
+    let index = cpu.cpu_index();
+    WAKE_LINE.get_for(index).fetch_add(1, Ordering::SeqCst);
+    // The calling CPU is awake, and restarts its tick before halting again.
+    if index != percpu::cpu_index() && TICKLESS.get_for(index).load(Ordering::SeqCst) {
+        crate::lapic::send_wakeup(cpu.apic_id());
+    }
+}
@@ -1,15 +1,67 @@
 use x86_64::structures::idt::InterruptStackFrame;
 
 const RTC_INTERRUPT_VECTOR: u8 = crate::pic::PIC_2_OFFSET;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const PAGE_FAULT_VECTOR: u8 = 14;
 
 pub fn default_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
+    if crate::percpu::is_installed() {
+        crate::stats::count_vector(index);
+    }
+    if index == PAGE_FAULT_VECTOR {
+        crate::multitask::check_stack_overflow();
+    }
     panic!(
         "Unhandled exception: vector = {}, error code = {:?}\n\nstack frame: {:#?}",
         index, error_code, stack_frame
     );
 }
 
+pub extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    if crate::percpu::is_installed() {
+        crate::stats::count_vector(DOUBLE_FAULT_VECTOR);
+    }
+    // A task that runs into its guard page faults again pushing the page
+    // fault's frame, which ends up here.
+    crate::multitask::check_stack_overflow();
+    panic!(
+        "Double fault: error code = {}\n\nstack frame: {:#?}",
+        error_code, stack_frame
+    );
+}
+
 pub extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter(RTC_INTERRUPT_VECTOR);
     crate::rtc::on_interrupt();
     crate::pic::send_eoi(RTC_INTERRUPT_VECTOR);
+    crate::percpu::irq_exit();
+}
+
+pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter(crate::keyboard::INTERRUPT_VECTOR);
+    crate::keyboard::on_interrupt();
+    crate::pic::send_eoi(crate::keyboard::INTERRUPT_VECTOR);
+    crate::percpu::irq_exit();
+}
+
+// Only sent to end a `hlt`; the interrupted idle loop does the rest.
+pub extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter(crate::lapic::WAKEUP_VECTOR);
+    crate::lapic::eoi();
+    crate::percpu::irq_exit();
+}
+
+pub extern "x86-interrupt" fn tlb_flush_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter(crate::lapic::TLB_FLUSH_VECTOR);
+    crate::lapic::ack_tlb_flush();
+    crate::lapic::eoi();
+    crate::percpu::irq_exit();
+}
+
+// Spurious local APIC interrupts must not be acknowledged with an EOI.
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::stats::count_vector(crate::lapic::SPURIOUS_VECTOR);
 }
@@ -1,7 +1,7 @@
 use lazy_static::lazy_static;
-use x86_64::VirtAddr;
 use x86_64::set_general_handler;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 const TIMER_INTERRUPT_VECTOR: u8 = crate::pic::PIC_1_OFFSET;
 const RTC_INTERRUPT_VECTOR: u8 = crate::pic::PIC_2_OFFSET;
@@ -12,12 +12,42 @@ lazy_static! {
         use handlers::*;
 
         set_general_handler!(&mut idt, default_handler, 0..=31);
+        // Runs on its own IST stack so a blown kernel stack still gets reported.
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_addr(VirtAddr::new(crate::watchdog::nmi_handler_addr()))
+                .set_stack_index(crate::gdt::NMI_IST_INDEX);
+        }
         unsafe {
             idt[TIMER_INTERRUPT_VECTOR].set_handler_addr(VirtAddr::new(
                 crate::multitask::timer_interrupt_handler_addr(),
             ));
         }
+        unsafe {
+            idt[crate::lapic::TIMER_VECTOR].set_handler_addr(VirtAddr::new(
+                crate::multitask::lapic_timer_interrupt_handler_addr(),
+            ));
+        }
+        unsafe {
+            idt[crate::multitask::YIELD_VECTOR].set_handler_addr(VirtAddr::new(
+                crate::multitask::yield_interrupt_handler_addr(),
+            ));
+        }
+        unsafe {
+            idt[crate::syscall::SYSCALL_VECTOR]
+                .set_handler_addr(VirtAddr::new(crate::syscall::int80_handler_addr()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt[RTC_INTERRUPT_VECTOR].set_handler_fn(rtc_interrupt_handler);
+        idt[crate::keyboard::INTERRUPT_VECTOR].set_handler_fn(keyboard_interrupt_handler);
+        idt[crate::lapic::WAKEUP_VECTOR].set_handler_fn(wakeup_interrupt_handler);
+        idt[crate::lapic::TLB_FLUSH_VECTOR].set_handler_fn(tlb_flush_interrupt_handler);
+        idt[crate::lapic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -0,0 +1,104 @@
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::channel::{Receiver, Sender};
+use crate::time::{self, Duration};
+
+const DATA_PORT: u16 = 0x60;
+// Reads give the status, writes go to the controller itself.
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// An absent controller reads as all ones.
+const NO_CONTROLLER: u8 = 0xFF;
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const CONFIG_FIRST_PORT_IRQ: u8 = 1 << 0;
+// Translates what the keyboard sends to scancode set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+const CONTROLLER_TIMEOUT: Duration = Duration::from_millis(10);
+// The controller buffers a few bytes at most.
+const MAX_STALE_BYTES: usize = 16;
+// Scancodes the consumer has not taken yet; more are dropped.
+const BUFFER_LEN: usize = 64;
+
+const IRQ: u8 = 1;
+pub const INTERRUPT_VECTOR: u8 = crate::pic::PIC_1_OFFSET + IRQ;
+
+static SCANCODES: Once<(Sender<u8>, Receiver<u8>)> = Once::new();
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn read_data() -> Option<u8> {
+    time::poll_until(CONTROLLER_TIMEOUT, || {
+        (status() & STATUS_OUTPUT_FULL != 0).then(|| unsafe { Port::<u8>::new(DATA_PORT).read() })
+    })
+    .ok()
+}
+
+fn write(port: u16, value: u8) -> Option<()> {
+    time::poll_until(CONTROLLER_TIMEOUT, || {
+        (status() & STATUS_INPUT_FULL == 0).then_some(())
+    })
+    .ok()?;
+    unsafe { Port::<u8>::new(port).write(value) };
+    Some(())
+}
+
+fn enable_interrupt() -> Option<()> {
+    write(COMMAND_PORT, COMMAND_READ_CONFIG)?;
+    let config = read_data()?;
+    write(COMMAND_PORT, COMMAND_WRITE_CONFIG)?;
+    write(
+        DATA_PORT,
+        config | CONFIG_FIRST_PORT_IRQ | CONFIG_TRANSLATION,
+    )
+}
+
+/// Turns on interrupts for the keyboard on the first port of the i8042
+/// controller, keeping the rest of what the firmware set up. Requires the
+/// heap. Returns false if there is no controller or it does not respond.
+pub fn init() -> bool {
+    if status() == NO_CONTROLLER {
+        return false;
+    }
+
+    // Bytes left over from the firmware would be taken for key presses.
+    for _ in 0..MAX_STALE_BYTES {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+
+    if enable_interrupt().is_none() {
+        return false;
+    }
+
+    SCANCODES.call_once(|| crate::multitask::bounded(BUFFER_LEN));
+    crate::pic::enable_irq(IRQ);
+    true
+}
+
+/// Receives the scancodes of key presses and releases, in set 1, as they
+/// come in. `None` if there is no keyboard.
+pub fn scancodes() -> Option<Receiver<u8>> {
+    SCANCODES.get().map(|(_, receiver)| receiver.clone())
+}
+
+/// Takes the scancode off the controller, which holds back the next one
+/// until then. It is handed on by a bottom half, after the EOI.
+pub fn on_interrupt() {
+    let scancode = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    crate::softirq::raise(deliver, scancode as usize);
+}
+
+// Drops the scancode if the consumer has fallen behind.
+fn deliver(scancode: usize) {
+    if let Some((sender, _)) = SCANCODES.get() {
+        let _ = sender.try_send(scancode as u8);
+    }
+}
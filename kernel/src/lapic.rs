@@ -0,0 +1,445 @@
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering, fence};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu::{self, Feature};
+use crate::percpu::{self, percpu};
+use crate::smp::MAX_CPUS;
+
+pub const TIMER_VECTOR: u8 = 0x40;
+pub const WAKEUP_VECTOR: u8 = 0x41;
+// In a higher priority class than the tick, so a CPU flushes before it
+// switches to a task whose mappings changed.
+pub const TLB_FLUSH_VECTOR: u8 = 0xF0;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const APIC_MMIO_SIZE: u64 = 0x1000;
+
+const REG_ID: usize = 0x020;
+const REG_EOI: usize = 0x0B0;
+const REG_SVR: usize = 0x0F0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_PERF: usize = 0x340;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const ICR_DESTINATION_SHIFT: u32 = 24;
+
+const CALIBRATION_US: u64 = 10_000;
+const TLB_FLUSH_NOT_LISTENING: u64 = u64::MAX;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1,
+    TscDeadline = 2,
+}
+
+impl TimerMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Periodic,
+            2 => Self::TscDeadline,
+            _ => Self::OneShot,
+        }
+    }
+
+    fn lvt_bits(self) -> u32 {
+        match self {
+            Self::OneShot => LVT_TIMER_ONE_SHOT,
+            Self::Periodic => LVT_TIMER_PERIODIC,
+            Self::TscDeadline => LVT_TIMER_TSC_DEADLINE,
+        }
+    }
+}
+
+static APIC_BASE: AtomicU64 = AtomicU64::new(0);
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+// Bumped by every TLB flush broadcast; CPUs acknowledge it by copying it.
+static TLB_FLUSH_GENERATION: AtomicU64 = AtomicU64::new(0);
+// Held while waiting for acknowledgements, one broadcast at a time.
+static TLB_FLUSH_LOCK: Mutex<()> = Mutex::new(());
+
+// Timer state is per CPU: every local APIC has its own timer.
+percpu! {
+    static TIMER_MODE: AtomicU8 = AtomicU8::new(TimerMode::OneShot as u8);
+    // Non-zero while a one-shot or TSC-deadline timer emulates a periodic tick.
+    static REARM_INTERVAL_US: AtomicU64 = AtomicU64::new(0);
+    static NEXT_TSC_DEADLINE: AtomicU64 = AtomicU64::new(0);
+    // Last broadcast generation the CPU flushed for, until its APIC is set
+    // up `TLB_FLUSH_NOT_LISTENING`.
+    static TLB_FLUSHED: AtomicU64 = AtomicU64::new(TLB_FLUSH_NOT_LISTENING);
+}
+
+fn read(reg: usize) -> u32 {
+    let base = APIC_BASE.load(Ordering::Acquire) as usize;
+    unsafe { ptr::read_volatile((base + reg) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+    let base = APIC_BASE.load(Ordering::Acquire) as usize;
+    unsafe { ptr::write_volatile((base + reg) as *mut u32, value) }
+}
+
+pub fn is_present() -> bool {
+    cpu::has(Feature::Apic)
+}
+
+pub fn is_enabled() -> bool {
+    APIC_BASE.load(Ordering::Acquire) != 0
+}
+
+pub fn supports_tsc_deadline() -> bool {
+    cpu::has(Feature::TscDeadline) && crate::tsc::frequency_hz() != 0
+}
+
+/// Enables the local APIC and calibrates its timer against the HPET, or the
+/// PIT when there is no HPET.
+///
+/// Returns `false` when the CPU has no local APIC, in which case the PIT stays
+/// the only tick source.
+pub fn init() -> bool {
+    if !is_present() {
+        return false;
+    }
+
+    interrupts::without_interrupts(|| {
+        let base = enable_local();
+        crate::paging::map_mmio(base, APIC_MMIO_SIZE);
+        APIC_BASE.store(base, Ordering::Release);
+        enable_registers();
+    });
+
+    let hz = calibrate_timer();
+    TIMER_HZ.store(hz, Ordering::Release);
+    true
+}
+
+/// Enables the local APIC of an application processor. The MMIO window and
+/// timer calibration are shared with the bootstrap processor.
+pub fn init_ap() {
+    if !is_enabled() {
+        panic!("local APIC is not initialized");
+    }
+
+    interrupts::without_interrupts(|| {
+        enable_local();
+        enable_registers();
+    });
+}
+
+fn enable_local() -> u64 {
+    unsafe {
+        let mut base_msr = Msr::new(IA32_APIC_BASE);
+        let base_value = base_msr.read() | APIC_BASE_ENABLE;
+        base_msr.write(base_value);
+        base_value & APIC_BASE_ADDR_MASK
+    }
+}
+
+fn enable_registers() {
+    write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_LVT_PERF, LVT_MASKED);
+    crate::percpu::current().set_apic_id(id());
+
+    // From here on flush IPIs reach the CPU, so broadcasts wait for it. A
+    // change made before the generation was read is covered by the flush.
+    TLB_FLUSHED.get().store(
+        TLB_FLUSH_GENERATION.load(Ordering::Acquire),
+        Ordering::Release,
+    );
+    tlb::flush_all();
+}
+
+fn calibrate_timer() -> u64 {
+    interrupts::without_interrupts(|| {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_MASKED | LVT_TIMER_ONE_SHOT);
+        write(REG_TIMER_INITIAL, u32::MAX);
+
+        crate::clock::busy_wait_us(CALIBRATION_US);
+
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+        write(REG_TIMER_INITIAL, 0);
+        elapsed as u64 * 1_000_000 / CALIBRATION_US
+    })
+}
+
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, apic_id << ICR_DESTINATION_SHIFT);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+pub fn send_nmi(apic_id: u32) {
+    send_ipi(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+}
+
+/// Interrupts the target with [`WAKEUP_VECTOR`], which does nothing but end
+/// a `hlt`.
+pub fn send_wakeup(apic_id: u32) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | WAKEUP_VECTOR as u32,
+    );
+}
+
+/// Has every other CPU flush its TLB with [`TLB_FLUSH_VECTOR`] and waits
+/// until they have. Works with interrupts disabled: while waiting, the CPU
+/// answers broadcasts of others itself.
+pub fn broadcast_tlb_flush() {
+    if !is_enabled() {
+        return;
+    }
+    let listening =
+        |cpu: usize| TLB_FLUSHED.get_for(cpu).load(Ordering::Acquire) != TLB_FLUSH_NOT_LISTENING;
+    let me = percpu::cpu_index();
+    if !(0..MAX_CPUS).any(|cpu| cpu != me && listening(cpu)) {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = TLB_FLUSH_LOCK.try_lock() {
+            break guard;
+        }
+        ack_missed_tlb_flush();
+        spin_loop();
+    };
+    // The IPI skips the sending CPU, which must not change before it is sent.
+    let (me, generation) = interrupts::without_interrupts(|| {
+        let generation = TLB_FLUSH_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+        send_ipi(
+            0,
+            ICR_DELIVERY_FIXED
+                | ICR_LEVEL_ASSERT
+                | ICR_ALL_EXCLUDING_SELF
+                | TLB_FLUSH_VECTOR as u32,
+        );
+        (percpu::cpu_index(), generation)
+    });
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != me) {
+        let flushed = TLB_FLUSHED.get_for(cpu);
+        loop {
+            let acked = flushed.load(Ordering::Acquire);
+            if acked == TLB_FLUSH_NOT_LISTENING || acked >= generation {
+                break;
+            }
+            ack_missed_tlb_flush();
+            spin_loop();
+        }
+    }
+}
+
+/// Flushes the calling CPU's TLB for a broadcast and acknowledges it.
+pub fn ack_tlb_flush() {
+    // Read first: the flush then comes after the change being broadcast.
+    let generation = TLB_FLUSH_GENERATION.load(Ordering::Acquire);
+    tlb::flush_all();
+    TLB_FLUSHED.get().fetch_max(generation, Ordering::AcqRel);
+}
+
+// A CPU spinning with interrupts disabled would keep the broadcasting one
+// waiting for its acknowledgement forever.
+fn ack_missed_tlb_flush() {
+    let acked = TLB_FLUSHED.get().load(Ordering::Acquire);
+    if acked != TLB_FLUSH_NOT_LISTENING && acked < TLB_FLUSH_GENERATION.load(Ordering::Acquire) {
+        interrupts::without_interrupts(ack_tlb_flush);
+    }
+}
+
+/// Delivers performance counter overflows as NMIs. The APIC masks the entry
+/// again on delivery, so the handler calls this to re-arm it.
+pub fn set_perf_counter_nmi() {
+    write(REG_LVT_PERF, LVT_DELIVERY_NMI);
+}
+
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI; the target starts in real mode at `vector_page * 4 KiB`.
+pub fn send_startup(apic_id: u32, vector_page: u8) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector_page as u32,
+    );
+}
+
+pub fn timer_frequency_hz() -> u64 {
+    TIMER_HZ.load(Ordering::Acquire)
+}
+
+pub fn timer_mode() -> TimerMode {
+    TimerMode::from_u8(TIMER_MODE.get().load(Ordering::Acquire))
+}
+
+/// TSC-deadline is preferred because it is programmed in absolute TSC cycles
+/// and does not accumulate drift when re-armed.
+pub fn preferred_timer_mode() -> TimerMode {
+    if supports_tsc_deadline() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::Periodic
+    }
+}
+
+fn initial_count(microseconds: u64) -> u32 {
+    let count = (timer_frequency_hz() as u128 * microseconds as u128) / 1_000_000;
+    count.clamp(1, u32::MAX as u128) as u32
+}
+
+fn set_lvt(mode: TimerMode) {
+    write(REG_TIMER_INITIAL, 0);
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, mode.lvt_bits() | TIMER_VECTOR as u32);
+    TIMER_MODE.get().store(mode as u8, Ordering::Release);
+
+    if mode == TimerMode::TscDeadline {
+        // The LVT write must be visible before the deadline MSR is armed.
+        fence(Ordering::SeqCst);
+    }
+}
+
+fn arm_tsc_deadline(deadline: u64) {
+    NEXT_TSC_DEADLINE.get().store(deadline, Ordering::Release);
+    unsafe {
+        Msr::new(IA32_TSC_DEADLINE).write(deadline);
+    }
+}
+
+fn arm(mode: TimerMode, microseconds: u64) {
+    match mode {
+        TimerMode::TscDeadline => {
+            let cycles = crate::tsc::micros_to_cycles(microseconds).unwrap_or(0);
+            arm_tsc_deadline(crate::tsc::read().saturating_add(cycles.max(1)));
+        }
+        TimerMode::OneShot | TimerMode::Periodic => {
+            write(REG_TIMER_INITIAL, initial_count(microseconds));
+        }
+    }
+}
+
+/// Starts a repeating timer interrupt every `interval_us` microseconds on the
+/// calling CPU.
+///
+/// One-shot and TSC-deadline modes have no hardware reload, so they are
+/// re-armed from [`on_timer_interrupt`].
+pub fn start_timer(mode: TimerMode, interval_us: u64) {
+    if !is_enabled() {
+        panic!("local APIC is not initialized");
+    }
+    if interval_us == 0 {
+        panic!("timer interval must be non-zero");
+    }
+    if mode == TimerMode::TscDeadline && !supports_tsc_deadline() {
+        panic!("TSC-deadline timer mode is not supported");
+    }
+
+    interrupts::without_interrupts(|| {
+        let rearm = if mode == TimerMode::Periodic {
+            0
+        } else {
+            interval_us
+        };
+        REARM_INTERVAL_US.get().store(rearm, Ordering::Release);
+        set_lvt(mode);
+        arm(mode, interval_us);
+    });
+}
+
+/// Arms a single timer interrupt `interval_us` microseconds from now,
+/// replacing any repeating timer.
+pub fn arm_oneshot(interval_us: u64) {
+    let mode = if supports_tsc_deadline() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::OneShot
+    };
+
+    interrupts::without_interrupts(|| {
+        REARM_INTERVAL_US.get().store(0, Ordering::Release);
+        set_lvt(mode);
+        arm(mode, interval_us);
+    });
+}
+
+#[allow(dead_code)]
+pub fn stop_timer() {
+    interrupts::without_interrupts(|| {
+        REARM_INTERVAL_US.get().store(0, Ordering::Release);
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, 0);
+        if timer_mode() == TimerMode::TscDeadline {
+            unsafe {
+                Msr::new(IA32_TSC_DEADLINE).write(0);
+            }
+        }
+    });
+}
+
+/// Re-arms timers that emulate a periodic tick. Called from the timer vector.
+pub fn on_timer_interrupt() {
+    let interval_us = REARM_INTERVAL_US.get().load(Ordering::Acquire);
+    if interval_us == 0 {
+        return;
+    }
+
+    match timer_mode() {
+        TimerMode::TscDeadline => {
+            let cycles = crate::tsc::micros_to_cycles(interval_us)
+                .unwrap_or(0)
+                .max(1);
+            let now = crate::tsc::read();
+            let mut deadline = NEXT_TSC_DEADLINE
+                .get()
+                .load(Ordering::Acquire)
+                .saturating_add(cycles);
+            // Skip missed ticks instead of firing a burst of back-to-back interrupts.
+            if deadline <= now {
+                deadline = now.saturating_add(cycles);
+            }
+            arm_tsc_deadline(deadline);
+        }
+        TimerMode::OneShot => arm(TimerMode::OneShot, interval_us),
+        TimerMode::Periodic => {}
+    }
+}
@@ -3,27 +3,72 @@
 #![no_std]
 #![no_main]
 
+mod acpi;
 mod asmtools;
+mod channel;
+mod clock;
+mod cpu;
 mod debug;
+mod fpu;
 mod gdt;
 mod gui;
 mod heap;
+mod hpet;
+mod idle;
 mod idt;
+mod keyboard;
+mod lapic;
 mod multitask;
 mod paging;
+mod percpu;
 mod pic;
 mod pit;
 mod rtc;
+mod smp;
+mod softirq;
+mod stats;
+mod sync;
+mod syscall;
+mod time;
+mod timer;
+mod tsc;
+mod watchdog;
+mod workqueue;
 
 extern crate alloc;
 
 use embedded_graphics::pixelcolor::Rgb888;
+use spin::Once;
 use x86_64::instructions::interrupts;
 
-use crate::multitask::Thread;
+use crate::channel::Sender;
+use crate::multitask::{Priority, Thread};
+use crate::sync::{Condvar, LockOwner, Mutex, RwLock, Semaphore};
+use crate::time::Duration;
 
 const RECT_SIZE: u32 = 300;
 const RECT_DELAY_MS: u64 = 4;
+const GUI3_TIME_SLICE: Duration = Duration::from_millis(2);
+const GUI2_RUN_TIME: Duration = Duration::from_secs(30);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+const SCHEDULER_TICK_US: u64 = 1_000;
+const GUI_FPS: u64 = 10;
+// Scancode set 1 key presses.
+const SCANCODE_T: u8 = 0x14;
+const SCANCODE_H: u8 = 0x23;
+const SCANCODE_SPACE: u8 = 0x39;
+
+// Tells the boot task to hand over to the green rectangle right away.
+static HANDOVER: Once<Sender<()>> = Once::new();
+// Where the red and the green rectangle are drawn.
+static LAYOUT: RwLock<[(i64, i64); 2]> = RwLock::new([(0, 0), (300, 300)])
+    .with_priority_inheritance()
+    .with_debug();
+// Set while drawing is paused; cleared with a notify.
+static PAUSED: Mutex<bool> = Mutex::new(false);
+static RESUMED: Condvar = Condvar::new();
+// One task listing is printed per permit.
+static REPORTS: Semaphore = Semaphore::new(0);
 
 fn init(boot_info_ptr: *const gui::BootInfo) {
     debug::println!("RUST OS loaded.");
@@ -31,60 +76,222 @@ fn init(boot_info_ptr: *const gui::BootInfo) {
     gdt::init();
     debug::println!("GDT loaded.");
 
+    cpu::init();
+    debug::println!("CPU features: {:?}.", cpu::features());
+
+    percpu::init();
+    debug::println!("Per-CPU data initialized.");
+
     idt::init();
     debug::println!("IDT loaded.");
 
+    syscall::init();
+    debug::println!("System calls initialized.");
+
     paging::init();
     debug::println!("Paging initialized.");
 
+    cpu::enable_features();
+
+    fpu::init();
+    debug::println!(
+        "FPU: {:?}, {} byte save area.",
+        fpu::mode(),
+        fpu::area_size()
+    );
+
     gui::init(boot_info_ptr);
     debug::println!("GUI Initialized.");
 
+    let boot_info = gui::boot_info_from_ptr(boot_info_ptr);
+    if acpi::init(boot_info.acpi_rsdp_addr) {
+        debug::println!("ACPI tables found.");
+    } else {
+        debug::println!("ACPI tables not available.");
+    }
+
     pic::init();
     debug::println!("PIC initialized.");
 
     rtc::init();
     debug::println!("RTC initialized.");
 
+    if hpet::init() {
+        debug::println!("HPET initialized: {} Hz.", hpet::frequency_hz());
+    } else {
+        debug::println!("HPET not present.");
+    }
+
+    tsc::init();
+    debug::println!("TSC calibrated: {} Hz.", tsc::frequency_hz());
+
+    if lapic::init() {
+        debug::println!(
+            "Local APIC initialized: timer {} Hz, {:?} mode.",
+            lapic::timer_frequency_hz(),
+            lapic::preferred_timer_mode()
+        );
+    } else {
+        debug::println!("Local APIC not present, falling back to PIT.");
+    }
+
+    clock::init();
+    debug::println!(
+        "Clock source: {:?}, {:?} resolution.",
+        clock::source(),
+        time::resolution()
+    );
+
+    // Ticks are only needed by the RTC fallback clock.
+    if !clock::needs_interrupts() {
+        rtc::stop_periodic_interrupt();
+        debug::println!("RTC periodic interrupt stopped.");
+    }
+
+    if !time::init_wall_clock() {
+        debug::println!("RTC unreadable, wall clock starts at the epoch.");
+    }
+    debug::println!("Wall clock: {} UTC.", time::now_utc());
+
     heap::init_heap();
     debug::println!("Heap initialized.");
 
-    multitask::init(1.0);
-    interrupts::enable();
+    multitask::init(SCHEDULER_TICK_US);
     debug::println!("Multitask initialized.");
+
+    watchdog::init();
+    debug::println!(
+        "Watchdog: {:?} NMI source, {} s threshold.",
+        watchdog::source(),
+        watchdog::threshold_secs()
+    );
+
+    let cpus = smp::init(boot_info.ap_trampoline_addr);
+    debug::println!("SMP: {} CPU(s) online.", cpus);
+
+    workqueue::init(cpus);
+    debug::println!("Workqueue: {} worker(s).", cpus);
+
+    if keyboard::init() {
+        debug::println!("PS/2 keyboard initialized.");
+    } else {
+        debug::println!("PS/2 keyboard not present.");
+    }
+
+    debug::println!(
+        "Tickless idle: {}.",
+        if idle::is_enabled() { "on" } else { "off" }
+    );
+
+    interrupts::enable();
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn _start(boot_info_ptr: *const gui::BootInfo) -> ! {
     init(boot_info_ptr);
 
+    timer::every(time::Duration::from_millis(1000 / GUI_FPS), || {
+        gui::GOP_SCREEN.lock().refresh();
+    });
+    timer::every(STATS_INTERVAL, || REPORTS.release());
+
     let threads = [
-        Thread::new(gui_update, 90),
-        Thread::new(gui2, 44),
-        Thread::new(gui3, 55),
+        Thread::new(gui2, 44).with_name("gui2"),
+        Thread::new(gui3, 55)
+            .with_name("gui3")
+            .with_priority(Priority::Low)
+            .with_time_slice(GUI3_TIME_SLICE),
+        Thread::new(report_tasks, 66)
+            .with_name("stats")
+            .with_priority(Priority::Idle),
+        Thread::new(console, 77).with_name("console"),
     ];
+    let (handover_sender, handover) = multitask::channel();
+    HANDOVER.call_once(|| handover_sender);
     for thread in &threads {
         thread.start();
     }
 
+    // After a while, or once H is pressed, the red rectangle stops and the
+    // green one takes over its spot and priority.
+    let _ = handover.recv_timeout(GUI2_RUN_TIME);
+    threads[0].stop();
+    let spot = LAYOUT.read()[0];
+    LAYOUT.write()[1] = spot;
+    threads[1].set_priority(Priority::Normal);
+
+    // The boot task is done; the CPU idles when nothing else is ready.
+    multitask::exit(0);
+}
+
+// Only prints when nothing else wants the CPU.
+fn report_tasks(_id: u16) {
     loop {
-        core::hint::spin_loop();
+        REPORTS.acquire();
+        stats::print_tasks();
+        print_lock_owner("GOP_SCREEN", gui::GOP_SCREEN.owner());
+        print_lock_owner("LAYOUT", LAYOUT.writer());
+    }
+}
+
+fn print_lock_owner(name: &str, owner: Option<LockOwner>) {
+    match owner {
+        Some(LockOwner {
+            slot,
+            location: Some(location),
+        }) => debug::println!("{} held by slot {}, locked at {}.", name, slot, location),
+        Some(LockOwner {
+            slot,
+            location: None,
+        }) => debug::println!("{} held by slot {}.", name, slot),
+        None => {}
+    }
+}
+
+// T prints the task list, space pauses and resumes drawing and H hands over
+// early. Ends at once without a keyboard.
+fn console(_id: u16) {
+    let Some(scancodes) = keyboard::scancodes() else {
+        return;
+    };
+    for scancode in scancodes.iter() {
+        match scancode {
+            SCANCODE_T => REPORTS.release(),
+            SCANCODE_SPACE => {
+                let mut paused = PAUSED.lock();
+                *paused = !*paused;
+                if !*paused {
+                    RESUMED.notify_all();
+                }
+            }
+            SCANCODE_H => {
+                if let Some(handover) = HANDOVER.get() {
+                    // Fails once the handover is done.
+                    let _ = handover.send(());
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 fn gui2(_id: u16) {
-    animate_rect(0, 0, |value| Rgb888::new(value, 0, 0));
+    animate_rect(0, |value| Rgb888::new(value, 0, 0));
 }
 
 fn gui3(_id: u16) {
-    animate_rect(300, 300, |value| Rgb888::new(0, value, 0));
+    animate_rect(1, |value| Rgb888::new(0, value, 0));
 }
 
-fn animate_rect(x: i64, y: i64, color: fn(u8) -> Rgb888) {
+fn animate_rect(index: usize, color: fn(u8) -> Rgb888) {
     loop {
         use gui::GOP_SCREEN;
 
         for value in (0..=255).chain((0..=255).rev()) {
+            drop(RESUMED.wait_while(PAUSED.lock(), |paused| *paused));
+            // Checked after a pause, so a stopped thread draws no more.
+            multitask::check_cancelled();
+            let (x, y) = LAYOUT.read()[index];
             GOP_SCREEN
                 .lock()
                 .fill_rect(x, y, RECT_SIZE, RECT_SIZE, color(value), 255);
@@ -92,11 +299,3 @@ fn animate_rect(x: i64, y: i64, color: fn(u8) -> Rgb888) {
         }
     }
 }
-
-fn gui_update(_id: u16) {
-    let fps = 10;
-    loop {
-        rtc::sleep(1000 / fps);
-        gui::GOP_SCREEN.lock().refresh();
-    }
-}
@@ -1,101 +1,631 @@
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use alloc::boxed::Box;
+use alloc::collections::{BinaryHeap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::arch::asm;
+use core::cmp::Reverse;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
 use core::{cell::Cell, mem, ptr};
+use spin::Mutex;
 use x86_64::instructions::{hlt, interrupts};
+use x86_64::registers::control::Cr2;
 use x86_64::registers::rflags::RFlags;
 use x86_64::registers::segmentation::{CS, SS, Segment};
 
-const MAX_TASK: usize = 32;
-const TASK_STACK_SIZE: usize = 16 * 1024;
+use crate::paging::PAGE_SIZE;
+use crate::percpu::{self, PerCpu, percpu};
+use crate::smp::MAX_CPUS;
+use crate::time::{Duration, Instant};
 
+pub use crate::channel::{bounded, channel};
+
+const DEFAULT_STACK_SIZE: usize = 16 * 1024;
+const IDLE_STACK_SIZE: usize = 8 * 1024;
+// Room for the entry frame, a saved context and a few calls.
+const MIN_STACK_SIZE: usize = 4 * 1024;
+// Bottom word of every task stack; found changed when a task overflowed it.
+const STACK_CANARY: u64 = 0x5354_4143_4b43_414e;
+// The rest of a new stack holds this, so untouched words can be told apart.
+const STACK_FILL: u64 = 0xcccc_cccc_cccc_cccc;
+
+const PRIORITIES: usize = 4;
+// Each priority has a level for tasks that gave up the CPU before their
+// slice ran out and one below it for tasks that used it up.
+const LEVELS: usize = PRIORITIES * 2;
+// Idle tasks are not protected from starvation, so only these levels are.
+const BOOSTED_LEVELS: usize = Priority::Idle as usize * 2;
+const DEFAULT_TIME_SLICE: Duration = Duration::from_millis(4);
+// Longest panic message kept for a `JoinHandle`; longer ones are cut off.
+// Small enough that `Result<T, JoinError>` stays cheap to move.
+const JOIN_ERROR_MESSAGE_BYTES: usize = 112;
+// Reasons shown for tasks blocked in `park` and `JoinHandle::join`.
+const PARK_REASON: &str = "park";
+const JOIN_REASON: &str = "join";
+
+/// Exit code of a spawned task that panicked.
+pub const EXIT_PANICKED: i32 = 101;
+/// Exit code of a task ended by [`Thread::stop`] or [`check_cancelled`].
+pub const EXIT_STOPPED: i32 = -1;
+// A task waiting this long runs next, whatever its priority, unless Idle.
+const STARVATION_NS: u64 = 100_000_000;
+
+// Extended register state lives in a separate per-task area (see `fpu`).
 const SAVED_GPR_BYTES: usize = 15 * 8;
-const SAVED_XMM_BYTES: usize = 16 * 16;
-const CONTEXT_PREFIX_BYTES: usize = SAVED_GPR_BYTES + SAVED_XMM_BYTES; // 0x178
 const IRET_FRAME_BYTES: usize = 3 * 8;
-const SAVED_CONTEXT_BYTES: usize = CONTEXT_PREFIX_BYTES + IRET_FRAME_BYTES; // 0x190
+const SAVED_CONTEXT_BYTES: usize = SAVED_GPR_BYTES + IRET_FRAME_BYTES; // 0x90
 const TASK_ENTRY_STACK_RESERVE_QWORDS: usize = 3;
 
+// Must match the `source` arguments of `TIMER_ENTRY` in asmtools.rs.
+const TICK_SOURCE_PIT: u64 = 0;
+const TICK_SOURCE_LAPIC: u64 = 1;
+const TICK_SOURCE_YIELD: u64 = 2;
+
+/// Software interrupt taken by `yield_now`; enters the scheduler the same
+/// way the tick does.
+pub const YIELD_VECTOR: u8 = 0x42;
+
 const _: [(); 0x78] = [(); SAVED_GPR_BYTES];
-const _: [(); 0x100] = [(); SAVED_XMM_BYTES];
-const _: [(); 0x178] = [(); CONTEXT_PREFIX_BYTES];
 const _: [(); 0x18] = [(); IRET_FRAME_BYTES];
-const _: [(); 0x190] = [(); SAVED_CONTEXT_BYTES];
+const _: [(); 0x90] = [(); SAVED_CONTEXT_BYTES];
 
+/// Registers pushed by the timer and NMI entries in asmtools.rs, followed by
+/// the start of the interrupt frame.
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct SavedContext {
-    rax: u64,
-    rbx: u64,
-    rcx: u64,
-    rdx: u64,
-    rsi: u64,
-    rdi: u64,
-    rbp: u64,
-    r8: u64,
-    r9: u64,
-    r10: u64,
-    r11: u64,
-    r12: u64,
-    r13: u64,
-    r14: u64,
-    r15: u64,
-    xmm: [[u8; 16]; 16],
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-}
-
-const _: [(); 0x78] = [(); mem::offset_of!(SavedContext, xmm)];
-const _: [(); 0x178] = [(); mem::offset_of!(SavedContext, rip)];
-const _: [(); 0x180] = [(); mem::offset_of!(SavedContext, cs)];
-const _: [(); 0x188] = [(); mem::offset_of!(SavedContext, rflags)];
-const _: [(); 0x190] = [(); mem::size_of::<SavedContext>()];
+pub struct SavedContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+}
+
+const _: [(); 0x78] = [(); mem::offset_of!(SavedContext, rip)];
+const _: [(); 0x80] = [(); mem::offset_of!(SavedContext, cs)];
+const _: [(); 0x88] = [(); mem::offset_of!(SavedContext, rflags)];
+const _: [(); 0x90] = [(); mem::size_of::<SavedContext>()];
+
+/// Scheduling priority. A ready task of a higher priority always runs
+/// before one of a lower priority, except that any task waiting for
+/// `STARVATION_NS` gets a slice, unless it is `Idle`. Higher priorities
+/// compare as smaller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal = 1,
+    Low = 2,
+    /// Only runs when nothing else is ready.
+    Idle = 3,
+}
 
 #[derive(Clone, Copy)]
 struct TaskContext {
+    // Zero for spawned closures.
+    id: u16,
+    name: &'static str,
     saved_rsp: usize,
     ready: bool,
+    // Set while a CPU executes the task or is still switching away from it.
+    running: bool,
+    // Set while the slot sits in some CPU's run queue. Entries left behind by
+    // a stopped task are dropped when they are popped.
+    queued: bool,
+    // Set by `park` and `sleep_until` until the task is woken; a blocked
+    // task is not queued again when it is switched away from.
+    blocked: bool,
+    // A wakeup that arrived while the task was not parked.
+    unparked: bool,
+    // Deadline of the current `sleep`, matched against sleep queue entries.
+    wake_at: Option<u64>,
+    // What the task is blocked on, and whether it is in `sleep_until`.
+    block_reason: &'static str,
+    sleeping: bool,
+    priority: Priority,
+    // Set when the task used up its last slice, which moves it to the lower
+    // level of its priority; cleared when it blocks and wakes up.
+    demoted: bool,
+    time_slice_ns: u64,
+    // CPU time used of the current slice.
+    slice_used_ns: u64,
+    // When the task was last queued, for starvation protection.
+    queued_ns: u64,
+    runtime_ns: u64,
+    // Times the task was switched in.
+    switches: u64,
+    // Set when the task ended; its slot is freed once no CPU runs on its
+    // stack any more.
+    exited: bool,
+    exit_code: i32,
+    created_ns: u64,
+    // Priorities lent by locks this task holds, counted per priority, one
+    // for each lock with a waiter at it.
+    inherited: [u16; PRIORITIES],
+    // Tells this task apart from others that used its slot before.
+    serial: u64,
+    // Set by `Thread::stop` on a task that already runs.
+    cancelled: bool,
+}
+
+impl TaskContext {
+    fn effective_priority(&self) -> Priority {
+        [
+            Priority::High,
+            Priority::Normal,
+            Priority::Low,
+            Priority::Idle,
+        ]
+        .into_iter()
+        .zip(self.inherited)
+        .find(|&(_, count)| count != 0)
+        .map_or(self.priority, |(inherited, _)| inherited.min(self.priority))
+    }
+
+    fn level(&self) -> usize {
+        self.effective_priority() as usize * 2 + self.demoted as usize
+    }
+
+    fn state(&self) -> TaskState {
+        if self.exited {
+            TaskState::Exited(self.exit_code)
+        } else if self.blocked {
+            match self.wake_at {
+                Some(nanos) if self.sleeping => TaskState::Sleeping(Instant::from_nanos(nanos)),
+                _ => TaskState::Blocked(self.block_reason),
+            }
+        } else if self.running {
+            TaskState::Running
+        } else {
+            TaskState::Ready
+        }
+    }
+
+    fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+        self.demoted = false;
+    }
+
+    // Demoted tasks run less often but for longer at a time.
+    fn slice_ns(&self) -> u64 {
+        self.time_slice_ns << self.demoted as u32
+    }
+
+    /// Marks the task queued from `now` and returns the level to queue it at.
+    fn requeue(&mut self, now: u64) -> usize {
+        self.queued = true;
+        self.queued_ns = now;
+        self.slice_used_ns = 0;
+        self.level()
+    }
+}
+
+enum TaskEntry {
+    Function(fn(u16)),
+    Closure(Box<dyn FnOnce() + Send>),
 }
 
-#[derive(Clone, Copy)]
 struct TaskStart {
-    entry: fn(u16),
-    id: u16,
+    // Taken by the task when it starts running.
+    entry: Option<TaskEntry>,
+    // Packet of a spawned task, kept alive until the task is gone and told
+    // about a panic.
+    join: Option<Arc<dyn JoinPacket>>,
+}
+
+/// How new task stacks are protected against overflow. Both put a canary
+/// at the bottom of the stack, checked whenever its task is switched away
+/// from.
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackGuard {
+    /// An unmapped page below the stack as well, so an overflow faults at
+    /// the instruction that caused it. Costs a page per task.
+    Page = 0,
+    /// Only the canary, which catches an overflow after the fact.
+    Canary = 1,
 }
 
+static STACK_GUARD: AtomicU8 = AtomicU8::new(StackGuard::Page as u8);
+
+/// Sets how stacks of tasks started from now on are protected.
+#[allow(dead_code)]
+pub fn set_stack_guard(guard: StackGuard) {
+    STACK_GUARD.store(guard as u8, Ordering::Relaxed);
+}
+
+pub fn stack_guard() -> StackGuard {
+    match STACK_GUARD.load(Ordering::Relaxed) {
+        0 => StackGuard::Page,
+        _ => StackGuard::Canary,
+    }
+}
+
+percpu! {
+    // Guard page of the stack the CPU's task runs on, zero if it has none,
+    // and the task's slot, for reporting overflows from the fault handlers.
+    static GUARD_PAGE: AtomicUsize = AtomicUsize::new(0);
+    static GUARD_SLOT: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// A task stack on the heap, above its guard page if it has one.
+struct TaskStack {
+    ptr: *mut u8,
+    layout: Layout,
+    guard_page: bool,
+}
+
+// Only touched through the scheduler lock, or by the task running on it.
+unsafe impl Send for TaskStack {}
+
+impl TaskStack {
+    // A guard page is carved out of `size`, so that a power-of-two size
+    // allocates no more than that from the buddy heap, as long as at least
+    // `MIN_STACK_SIZE` is left.
+    fn new(size: usize, guard: StackGuard) -> Self {
+        let guard_page = guard == StackGuard::Page;
+        let (layout, size) = if guard_page {
+            let total = size
+                .next_multiple_of(PAGE_SIZE as usize)
+                .max(MIN_STACK_SIZE + PAGE_SIZE as usize);
+            let layout = Layout::from_size_align(total, PAGE_SIZE as usize);
+            (layout, total - PAGE_SIZE as usize)
+        } else {
+            let size = size.next_multiple_of(16);
+            (Layout::from_size_align(size, 16), size)
+        };
+        let layout = layout.expect("task stack too large");
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        let stack = Self {
+            ptr,
+            layout,
+            guard_page,
+        };
+        let words = size / mem::size_of::<u64>();
+        unsafe {
+            let base = stack.base() as *mut u64;
+            core::slice::from_raw_parts_mut(base, words).fill(STACK_FILL);
+            ptr::write(base, STACK_CANARY);
+        }
+        if guard_page {
+            crate::paging::set_guard_page(ptr as u64, true);
+        }
+        stack
+    }
+
+    fn base(&self) -> usize {
+        self.ptr as usize + self.guard_page as usize * PAGE_SIZE as usize
+    }
+
+    fn top(&self) -> usize {
+        self.ptr as usize + self.layout.size()
+    }
+
+    fn guard_page(&self) -> usize {
+        if self.guard_page {
+            self.ptr as usize
+        } else {
+            0
+        }
+    }
+
+    fn canary_intact(&self) -> bool {
+        unsafe { ptr::read_volatile(self.base() as *const u64) == STACK_CANARY }
+    }
+
+    /// Most of the stack ever used, going by the words that no longer hold
+    /// the fill pattern.
+    fn high_water(&self) -> usize {
+        let mut addr = self.base() + mem::size_of::<u64>();
+        while addr < self.top() && unsafe { ptr::read_volatile(addr as *const u64) } == STACK_FILL {
+            addr += mem::size_of::<u64>();
+        }
+        self.top() - addr
+    }
+}
+
+impl Drop for TaskStack {
+    fn drop(&mut self) {
+        if self.guard_page {
+            crate::paging::set_guard_page(self.ptr as u64, false);
+        }
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// What a task is doing, as shown by [`tasks`].
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    /// Parked or waiting for a lock or queue, named by the reason.
+    Blocked(&'static str),
+    Sleeping(Instant),
+    /// Ended with the code, and not yet reaped.
+    Exited(i32),
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Running => f.write_str("running"),
+            Self::Ready => f.write_str("ready"),
+            Self::Blocked(reason) => write!(f, "blocked ({})", reason),
+            Self::Sleeping(until) => {
+                let left = until.as_nanos().saturating_sub(crate::clock::nanos());
+                write!(f, "sleeping ({} ms)", left / 1_000_000)
+            }
+            Self::Exited(code) => write!(f, "exited ({})", code),
+        }
+    }
+}
+
+/// A snapshot of one task, taken by [`tasks`].
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct TaskInfo {
+    pub slot: usize,
+    pub id: u16,
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Including a priority it inherited.
+    pub priority: Priority,
+    pub created: Instant,
+    pub cpu_time: Duration,
+    pub switches: u64,
+    /// `None` for the boot task, which runs on the boot stack.
+    pub stack: Option<StackUsage>,
+}
+
+/// How much of a task's stack has been used.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct StackUsage {
+    pub slot: usize,
+    pub id: u16,
+    pub size: usize,
+    /// Most bytes in use at any one time so far.
+    pub high_water: usize,
+}
+
+/// Panics if the page fault being handled hit the guard page of the task
+/// the calling CPU runs.
+pub fn check_stack_overflow() {
+    if !percpu::is_installed() {
+        return;
+    }
+    let guard = GUARD_PAGE.get().load(Ordering::Relaxed);
+    let addr = Cr2::read_raw() as usize;
+    if guard != 0 && (guard..guard + PAGE_SIZE as usize).contains(&addr) {
+        panic!(
+            "stack overflow in task {}",
+            GUARD_SLOT.get().load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Scheduling state of one CPU, kept in its per-CPU block. Other CPUs only
+/// lock it to hand over new tasks or to steal ready ones.
+pub struct RunQueue {
+    // `None` while the CPU runs its idle context.
+    current_task: Option<usize>,
+    // Stack of the bootstrap CPU's idle context. Application processors
+    // idle on the context they were started on.
+    idle_stack: Option<TaskStack>,
+    // Task switched away from by the last tick; released by `finish_switch`
+    // once the CPU no longer runs on its stack.
+    previous_task: Option<usize>,
+    idle_rsp: usize,
+    slice_start_ns: u64,
+    // The CPU's interrupt time when the slice started, which is not charged
+    // to the task.
+    slice_irq_ns: u64,
+    ready: ReadyQueue,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            current_task: None,
+            idle_stack: None,
+            previous_task: None,
+            idle_rsp: 0,
+            slice_start_ns: 0,
+            slice_irq_ns: 0,
+            ready: ReadyQueue::new(),
+        }
+    }
+}
+
+/// Ready tasks of one CPU, a FIFO per level; lower levels run first. A
+/// slot has at most one entry in all ready queues together, the one that
+/// `queued` stands for, and every level has room for every slot, so
+/// queueing from interrupt context never allocates.
+struct ReadyQueue {
+    levels: [VecDeque<usize>; LEVELS],
+}
+
+impl ReadyQueue {
+    const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; LEVELS],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(VecDeque::is_empty)
+    }
+
+    fn push(&mut self, slot: usize, level: usize) {
+        self.levels[level].push_back(slot);
+    }
+
+    /// Makes room for `slots` entries in every level.
+    fn reserve(&mut self, slots: usize) {
+        for queue in self.levels.iter_mut() {
+            queue.reserve(slots.saturating_sub(queue.len()));
+        }
+    }
+
+    /// Level and index of the entry of `slot`, if it is queued here.
+    fn find(&self, slot: usize) -> Option<(usize, usize)> {
+        self.levels.iter().enumerate().find_map(|(level, queue)| {
+            let index = queue.iter().position(|&queued| queued == slot)?;
+            Some((level, index))
+        })
+    }
+
+    /// Whether a task is waiting at a level that runs before `level`.
+    fn has_above(&self, level: usize) -> bool {
+        self.levels[..level].iter().any(|queue| !queue.is_empty())
+    }
+}
+
+// Per-slot tables grow as tasks are started and never shrink, so a slot
+// number left behind in a queue or a waiter stays a valid index.
 struct Scheduler {
-    contexts: [Option<TaskContext>; MAX_TASK],
-    starts: [Option<TaskStart>; MAX_TASK],
-    current_task: usize,
-    stacks: [[u8; TASK_STACK_SIZE]; MAX_TASK],
+    contexts: Vec<Option<TaskContext>>,
+    starts: Vec<Option<TaskStart>>,
+    // `None` for slot 0, which runs on the boot stack.
+    stacks: Vec<Option<TaskStack>>,
+    // Extended state save areas, allocated on first use of a slot and kept
+    // for reuse by the next task in it.
+    fpu_areas: Vec<usize>,
 }
 
 impl Scheduler {
     const fn new() -> Self {
         Self {
-            contexts: [None; MAX_TASK],
-            starts: [None; MAX_TASK],
-            current_task: 0,
-            stacks: [[0; TASK_STACK_SIZE]; MAX_TASK],
+            contexts: Vec::new(),
+            starts: Vec::new(),
+            stacks: Vec::new(),
+            fpu_areas: Vec::new(),
         }
     }
 
     fn reset(&mut self) {
-        self.contexts = [None; MAX_TASK];
-        self.starts = [None; MAX_TASK];
-        self.current_task = 0;
-        self.contexts[0] = Some(TaskContext {
+        self.contexts.clear();
+        self.starts.clear();
+        self.stacks.clear();
+        if self.fpu_areas.is_empty() {
+            self.fpu_areas.push(0);
+        }
+        self.starts.push(None);
+        self.stacks.push(None);
+        // Slot 0 is the boot context running on the bootstrap CPU.
+        self.contexts.push(Some(TaskContext {
+            id: 0,
+            name: "boot",
             saved_rsp: 0,
             ready: true,
-        });
+            running: true,
+            queued: false,
+            blocked: false,
+            unparked: false,
+            wake_at: None,
+            block_reason: "",
+            sleeping: false,
+            priority: Priority::Normal,
+            demoted: false,
+            time_slice_ns: DEFAULT_TIME_SLICE.as_nanos() as u64,
+            slice_used_ns: 0,
+            queued_ns: 0,
+            runtime_ns: 0,
+            switches: 0,
+            exited: false,
+            exit_code: 0,
+            created_ns: 0,
+            inherited: [0; PRIORITIES],
+            serial: 0,
+            cancelled: false,
+        }));
     }
 
-    fn clear_slot(&mut self, slot: usize) {
+    fn init_fpu_area(&mut self, slot: usize) -> usize {
+        match self.fpu_areas[slot] {
+            0 => self.fpu_areas[slot] = crate::fpu::alloc_area(),
+            area => crate::fpu::reset_area(area),
+        }
+        self.fpu_areas[slot]
+    }
+
+    /// Marks the task in `slot` exited and hands back its start data, to be
+    /// dropped outside the lock. The slot itself is freed by `reap_exited`.
+    fn exit_slot(&mut self, slot: usize, code: i32) -> Option<TaskStart> {
+        let ctx = self.contexts[slot].as_mut()?;
+        if ctx.exited {
+            return None;
+        }
+        ctx.exited = true;
+        ctx.exit_code = code;
+        ctx.ready = false;
+        self.starts[slot].take()
+    }
+
+    /// Frees one slot of an exited task that no CPU runs or queues any
+    /// more, handing back its stack to be dropped outside the lock. The boot
+    /// task has none; its slot is freed all the same, but never reused.
+    fn take_exited(&mut self) -> Option<Option<TaskStack>> {
+        let slot = (0..self.contexts.len()).find(|&slot| {
+            self.contexts[slot].is_some_and(|ctx| ctx.exited && !ctx.running && !ctx.queued)
+        })?;
         self.contexts[slot] = None;
-        self.starts[slot] = None;
+        Some(self.stacks[slot].take())
+    }
+
+    fn stack_bounds(&self, slot: usize) -> Option<(usize, usize)> {
+        let stack = self.stacks.get(slot)?.as_ref()?;
+        Some((stack.base(), stack.top()))
     }
 
-    fn stack_bounds(&self, slot: usize) -> (usize, usize) {
-        let base = self.stacks[slot].as_ptr() as usize;
-        (base, base + TASK_STACK_SIZE)
+    fn stack_usage(&self, slot: usize) -> Option<StackUsage> {
+        let stack = self.stacks.get(slot)?.as_ref()?;
+        Some(StackUsage {
+            slot,
+            id: self.contexts[slot].map_or(0, |ctx| ctx.id),
+            size: stack.top() - stack.base(),
+            high_water: stack.high_water(),
+        })
+    }
+
+    fn task_info(&self, slot: usize) -> Option<TaskInfo> {
+        let ctx = self.contexts[slot]?;
+        Some(TaskInfo {
+            slot,
+            id: ctx.id,
+            name: ctx.name,
+            state: ctx.state(),
+            priority: ctx.effective_priority(),
+            created: Instant::from_nanos(ctx.created_ns),
+            cpu_time: Duration::from_nanos(ctx.runtime_ns),
+            switches: ctx.switches,
+            stack: self.stack_usage(slot),
+        })
     }
 
     fn is_valid_saved_rsp(&self, slot: usize, saved_rsp: usize) -> bool {
@@ -113,11 +643,9 @@ impl Scheduler {
             return true;
         }
 
-        if slot >= MAX_TASK {
+        let Some((base, top)) = self.stack_bounds(slot) else {
             return false;
-        }
-
-        let (base, top) = self.stack_bounds(slot);
+        };
         let Some(frame_end) = saved_rsp.checked_add(SAVED_CONTEXT_BYTES) else {
             return false;
         };
@@ -125,198 +653,1372 @@ impl Scheduler {
         saved_rsp >= base && frame_end <= top
     }
 
-    fn next_ready_task_index(&self, current: usize) -> Option<usize> {
-        for offset in 1..=MAX_TASK {
-            let idx = (current + offset) % MAX_TASK;
-            if let Some(ctx) = self.contexts[idx] {
-                if ctx.ready && self.is_valid_saved_rsp(idx, ctx.saved_rsp) {
-                    return Some(idx);
+    fn is_runnable(&self, slot: usize) -> bool {
+        self.contexts[slot].is_some_and(|ctx| {
+            ctx.ready && !ctx.exited && self.is_valid_saved_rsp(slot, ctx.saved_rsp)
+        })
+    }
+
+    /// Level above Idle whose first task has waited for `STARVATION_NS`, the
+    /// longest waiting one if there are several.
+    fn starving_level(&self, ready: &ReadyQueue, now: u64) -> Option<usize> {
+        (0..BOOSTED_LEVELS)
+            .filter_map(|level| {
+                let ctx = self.contexts[*ready.levels[level].front()?]?;
+                let waited = now.saturating_sub(ctx.queued_ns);
+                (ctx.queued && waited >= STARVATION_NS).then_some((ctx.queued_ns, level))
+            })
+            .min()
+            .map(|(_, level)| level)
+    }
+
+    /// Pops the task to run next: a starving one, else the first runnable
+    /// task of the lowest non-empty level.
+    fn pop_ready(
+        &mut self,
+        ready: &mut ReadyQueue,
+        current: Option<usize>,
+        now: u64,
+    ) -> Option<usize> {
+        if let Some(level) = self.starving_level(ready, now)
+            && let Some(slot) = self.pop_level(&mut ready.levels[level], current)
+        {
+            return Some(slot);
+        }
+        (0..LEVELS).find_map(|level| self.pop_level(&mut ready.levels[level], current))
+    }
+
+    /// Pops the first runnable task of `queue`. Tasks still running elsewhere
+    /// are kept; `current` may be picked again although it is marked running.
+    fn pop_level(&mut self, queue: &mut VecDeque<usize>, current: Option<usize>) -> Option<usize> {
+        for _ in 0..queue.len() {
+            let slot = queue.pop_front()?;
+            let Some(ctx) = self.contexts[slot] else {
+                continue;
+            };
+            if !ctx.queued {
+                continue;
+            }
+            // Exited, or lost its context; the slot can be reaped once the
+            // entry is gone.
+            if !self.is_runnable(slot) {
+                if let Some(ctx) = self.contexts[slot].as_mut() {
+                    ctx.queued = false;
+                }
+                continue;
+            }
+            // Parked again after an `unpark` queued it; the next one will.
+            if ctx.blocked {
+                if let Some(ctx) = self.contexts[slot].as_mut() {
+                    ctx.queued = false;
                 }
+                continue;
+            }
+            if ctx.running && current != Some(slot) {
+                queue.push_back(slot);
+                continue;
+            }
+
+            if let Some(ctx) = self.contexts[slot].as_mut() {
+                ctx.queued = false;
             }
+            return Some(slot);
         }
         None
     }
 
-    fn allocate_slot(
-        &mut self,
-        entry: fn(u16),
-        id: u16,
-        cs: u64,
-        ss: u64,
-        rflags: u64,
-    ) -> Option<usize> {
-        for slot in 1..MAX_TASK {
-            if self.contexts[slot].is_none() {
-                self.contexts[slot] = Some(TaskContext {
-                    saved_rsp: self.init_task_context(slot, cs, ss, rflags),
-                    ready: true,
+    /// Takes a ready task from another CPU's run queue. Queues that are busy
+    /// are skipped rather than waited for, so two CPUs never wait on each other.
+    fn steal(&mut self, cpu: usize) -> Option<usize> {
+        for victim in (0..MAX_CPUS).filter(|&index| index != cpu) {
+            let Some(block) = percpu::get(victim) else {
+                continue;
+            };
+            let Some(mut run_queue) = block.run_queue.try_lock() else {
+                continue;
+            };
+
+            for queue in run_queue.ready.levels.iter_mut() {
+                let position = queue.iter().rposition(|&slot| {
+                    self.contexts[slot]
+                        .is_some_and(|ctx| ctx.queued && !ctx.running && !ctx.blocked)
+                        && self.is_runnable(slot)
                 });
-                self.starts[slot] = Some(TaskStart { entry, id });
-                return Some(slot);
+                if let Some(slot) = position.and_then(|index| queue.remove(index)) {
+                    if let Some(ctx) = self.contexts[slot].as_mut() {
+                        ctx.queued = false;
+                    }
+                    return Some(slot);
+                }
             }
         }
         None
     }
 
-    fn init_task_context(&mut self, slot: usize, cs: u64, ss: u64, rflags: u64) -> usize {
-        let (_, top) = self.stack_bounds(slot);
-        let stack_top = top & !0xF;
+    /// The task in `slot`, if it is still the one with `serial`.
+    fn task_mut(&mut self, slot: usize, serial: u64) -> Option<&mut TaskContext> {
+        self.contexts
+            .get_mut(slot)?
+            .as_mut()
+            .filter(|ctx| ctx.serial == serial)
+    }
 
-        // Reserve 24 bytes so task entry starts with SysV 16-byte alignment expectations.
-        // The first 16 bytes also serve as optional iret RSP/SS slots when needed.
-        let task_rsp = stack_top - TASK_ENTRY_STACK_RESERVE_QWORDS * mem::size_of::<u64>();
-        unsafe {
-            let stack_slots = task_rsp as *mut u64;
-            ptr::write(stack_slots, task_rsp as u64);
-            ptr::write(stack_slots.add(1), ss);
-            ptr::write(stack_slots.add(2), 0);
+    /// Puts a new task on `stack` into the first free slot, adding one if
+    /// all are taken.
+    fn allocate_slot(
+        &mut self,
+        start: TaskStart,
+        stack: TaskStack,
+        options: &TaskOptions,
+    ) -> usize {
+        let slot = match (1..self.contexts.len()).find(|&slot| self.contexts[slot].is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.contexts.push(None);
+                self.starts.push(None);
+                self.stacks.push(None);
+                self.fpu_areas.push(0);
+                self.contexts.len() - 1
+            }
+        };
+        self.stacks[slot] = Some(stack);
+        let now = crate::clock::nanos();
+        self.contexts[slot] = Some(TaskContext {
+            id: options.id,
+            name: options.name,
+            saved_rsp: self.init_task_context(slot),
+            ready: true,
+            running: false,
+            queued: true,
+            blocked: false,
+            unparked: false,
+            wake_at: None,
+            block_reason: "",
+            sleeping: false,
+            priority: options.priority,
+            demoted: false,
+            time_slice_ns: options.time_slice_ns,
+            slice_used_ns: 0,
+            queued_ns: now,
+            runtime_ns: 0,
+            switches: 0,
+            exited: false,
+            exit_code: 0,
+            created_ns: now,
+            inherited: [0; PRIORITIES],
+            serial: NEXT_SERIAL.fetch_add(1, Ordering::Relaxed),
+            cancelled: false,
+        });
+        self.starts[slot] = Some(start);
+        self.init_fpu_area(slot);
+        slot
+    }
+
+    fn init_task_context(&mut self, slot: usize) -> usize {
+        let (_, top) = self.stack_bounds(slot).expect("task slot without a stack");
+        init_entry_frame(top, task_entry_trampoline)
+    }
+
+    fn take_current_entry(&mut self, run_queue: &RunQueue) -> Option<(TaskEntry, u16)> {
+        let slot = run_queue.current_task?;
+        let id = self.contexts[slot]?.id;
+        Some((self.starts[slot].as_mut()?.entry.take()?, id))
+    }
+}
+
+// Shared by all CPUs; only ever locked with interrupts disabled. A CPU's run
+// queue is always locked before this.
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+static TICK_INTERVAL_US: AtomicU64 = AtomicU64::new(0);
+static REAP_QUEUED: AtomicBool = AtomicBool::new(false);
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(1);
+// Entries every ready level has room for: the most slots there have been.
+static READY_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
+// Sleeping tasks as (deadline, slot), earliest first. A task woken before its
+// deadline removes its own entry, so each task has at most one. Locked before
+// `SCHEDULER`; the tick only pops.
+static SLEEPERS: Mutex<BinaryHeap<Reverse<(u64, usize)>>> = Mutex::new(BinaryHeap::new());
+// Earliest deadline in `SLEEPERS`, so ticks skip the lock until it is due.
+static NEXT_WAKEUP_NS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// How a new task is set up, besides its entry.
+struct TaskOptions {
+    id: u16,
+    name: &'static str,
+    priority: Priority,
+    time_slice_ns: u64,
+    stack_size: usize,
+}
+
+/// Handle to a task running `entry`. Dropping it detaches the task, which
+/// runs on and is reaped once it exits.
+pub struct Thread {
+    entry: fn(u16),
+    id: u16,
+    name: &'static str,
+    priority: Cell<Priority>,
+    time_slice_ns: u64,
+    stack_size: usize,
+    // Slot and serial of the started task. The serial tells it apart from
+    // a later task in the same slot once it has been reaped.
+    task: Cell<Option<(usize, u64)>>,
+}
+
+impl Thread {
+    pub fn new(entry: fn(u16), id: u16) -> Self {
+        Self {
+            entry,
+            id,
+            name: "thread",
+            priority: Cell::new(Priority::Normal),
+            time_slice_ns: DEFAULT_TIME_SLICE.as_nanos() as u64,
+            stack_size: DEFAULT_STACK_SIZE,
+            task: Cell::new(None),
         }
+    }
 
-        let context_ptr = task_rsp - mem::size_of::<SavedContext>();
-        let context = context_ptr as *mut SavedContext;
+    /// Sets the name shown in task listings; "thread" by default.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
 
-        unsafe {
-            ptr::write_bytes(context as *mut u8, 0, mem::size_of::<SavedContext>());
-            (*context).rip = task_entry_trampoline as *const () as usize as u64;
-            (*context).cs = cs;
-            (*context).rflags = rflags;
+    /// Sets the priority the thread starts with; `Normal` by default.
+    pub fn with_priority(self, priority: Priority) -> Self {
+        self.priority.set(priority);
+        self
+    }
+
+    /// Sets how long the thread runs before another of its level gets the
+    /// CPU. Rounded up to whole scheduler ticks in practice.
+    pub fn with_time_slice(mut self, slice: Duration) -> Self {
+        if slice.is_zero() {
+            panic!("time slice must be non-zero");
         }
+        self.time_slice_ns = u64::try_from(slice.as_nanos()).unwrap_or(u64::MAX);
+        self
+    }
 
-        context_ptr
+    /// Sets the size of the thread's stack in bytes; 16 KiB by default. A
+    /// guard page, if any, is taken out of it.
+    #[allow(dead_code)]
+    pub fn with_stack_size(mut self, bytes: usize) -> Self {
+        if bytes < MIN_STACK_SIZE {
+            panic!(
+                "stack size {} is below the minimum of {}",
+                bytes, MIN_STACK_SIZE
+            );
+        }
+        self.stack_size = bytes;
+        self
     }
 
-    fn on_timer_interrupt(&mut self, current_rsp: usize) -> usize {
-        let current_slot = self.current_task;
-        let current_ready = self.is_valid_saved_rsp(current_slot, current_rsp);
-        if let Some(current) = self.contexts[current_slot].as_mut() {
-            current.saved_rsp = current_rsp;
-            current.ready = current_ready;
+    /// Starts the thread, unless its task is still alive. A thread that
+    /// exited starts over with a new task.
+    pub fn start(&self) {
+        if self.with_task(|ctx| !ctx.exited) == Some(true) {
+            return;
         }
 
-        let next_idx = self
-            .next_ready_task_index(self.current_task)
-            .unwrap_or(self.current_task);
+        let start = TaskStart {
+            entry: Some(TaskEntry::Function(self.entry)),
+            join: None,
+        };
+        let task = start_task(
+            start,
+            &TaskOptions {
+                id: self.id,
+                name: self.name,
+                priority: self.priority.get(),
+                time_slice_ns: self.time_slice_ns,
+                stack_size: self.stack_size,
+            },
+        );
+        self.task.set(Some(task));
+    }
+
+    /// Asks the thread to end. One that has not started running yet exits
+    /// at once with [`EXIT_STOPPED`]; otherwise the request is only noted,
+    /// for the thread to act on at its next [`check_cancelled`]. Its `park`
+    /// and `sleep` return early so it gets there; waits for locks, channels
+    /// and `join` go on until they are satisfied.
+    pub fn stop(&self) {
+        let Some((slot, serial)) = self.task.get() else {
+            return;
+        };
 
-        if let Some(next) = self.contexts[next_idx] {
-            if self.is_valid_saved_rsp(next_idx, next.saved_rsp) {
-                self.current_task = next_idx;
-                return next.saved_rsp;
+        let (start, started) = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let Some(ctx) = scheduler.task_mut(slot, serial) else {
+                return (None, false);
+            };
+            if ctx.exited {
+                return (None, false);
             }
+            ctx.cancelled = true;
+            let started = scheduler.starts[slot]
+                .as_ref()
+                .is_none_or(|start| start.entry.is_none());
+            if started {
+                (None, true)
+            } else {
+                (scheduler.exit_slot(slot, EXIT_STOPPED), false)
+            }
+        });
+        drop(start);
+        if started {
+            unpark_task(slot, Some(serial));
+        } else {
+            reap_exited();
         }
+    }
 
-        current_rsp
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
     }
 
-    fn current_task_start(&self) -> Option<TaskStart> {
-        self.starts[self.current_task]
+    /// What the thread is doing; `None` before it is started or once its
+    /// task has been reaped.
+    #[allow(dead_code)]
+    pub fn state(&self) -> Option<TaskState> {
+        self.with_task(|ctx| ctx.state())
     }
 
-    fn exit_current_task(&mut self) {
-        self.clear_slot(self.current_task);
+    /// CPU time consumed by the running thread, measured with the monotonic clock.
+    #[allow(dead_code)]
+    pub fn runtime_ns(&self) -> Option<u64> {
+        self.with_task(|ctx| ctx.runtime_ns)
+    }
+
+    /// Most bytes of its stack the thread has used so far.
+    #[allow(dead_code)]
+    pub fn stack_high_water(&self) -> Option<usize> {
+        let (slot, serial) = self.task.get()?;
+        interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.task_mut(slot, serial)?;
+            scheduler.stack_usage(slot).map(|usage| usage.high_water)
+        })
+    }
+
+    /// How many times the running thread has been switched in.
+    #[allow(dead_code)]
+    pub fn context_switches(&self) -> Option<u64> {
+        self.with_task(|ctx| ctx.switches)
+    }
+
+    #[allow(dead_code)]
+    pub fn priority(&self) -> Priority {
+        self.priority.get()
+    }
+
+    /// Changes the priority, also while the thread runs. The thread moves to
+    /// its new level the next time it is queued.
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.set(priority);
+        self.with_task(|ctx| ctx.set_priority(priority));
+    }
+
+    /// Wakes the thread from [`park`], or makes its next `park` return at
+    /// once. Never allocates, since every ready level has room for every
+    /// task, so bottom halves may call it.
+    pub fn unpark(&self) {
+        if let Some((slot, serial)) = self.task.get() {
+            unpark_task(slot, Some(serial));
+        }
+    }
+
+    /// Runs `f` on the context of the thread's task, if it has not been
+    /// reaped.
+    fn with_task<R>(&self, f: impl FnOnce(&mut TaskContext) -> R) -> Option<R> {
+        let (slot, serial) = self.task.get()?;
+        interrupts::without_interrupts(|| SCHEDULER.lock().task_mut(slot, serial).map(f))
     }
 }
 
-static mut SCHEDULER: Scheduler = Scheduler::new();
+/// Blocks the calling task until its [`Thread::unpark`] is called. A wakeup
+/// that came first is consumed instead. Like `std::thread::park`, callers
+/// check their condition in a loop, since a stale wakeup can end it early.
+/// Returns at once while the task is asked to stop.
+pub fn park() {
+    park_with(PARK_REASON, None);
+}
 
-#[inline(always)]
-unsafe fn scheduler_mut() -> &'static mut Scheduler {
-    unsafe { &mut *ptr::addr_of_mut!(SCHEDULER) }
+/// Like [`park`], but shows the task blocked on `reason` in task listings
+/// and, given a deadline, also returns once it has passed.
+pub fn park_with(reason: &'static str, deadline: Option<Instant>) {
+    block_current(reason, deadline, true);
 }
 
-#[inline(always)]
-unsafe fn scheduler_ref() -> &'static Scheduler {
-    unsafe { &*ptr::addr_of!(SCHEDULER) }
+/// Like [`park_with`], but a request to stop does not end it. For waits
+/// that park in a loop until some condition holds, which would otherwise
+/// spin once the task is asked to stop.
+pub fn park_uncancellable(reason: &'static str, deadline: Option<Instant>) {
+    block_current(reason, deadline, false);
 }
 
-pub struct Thread {
-    entry: fn(u16),
-    id: u16,
-    slot: Cell<Option<usize>>,
+fn block_current(reason: &'static str, deadline: Option<Instant>, cancellable: bool) {
+    let deadline_ns = deadline.map(|deadline| deadline.as_nanos());
+    if percpu::in_interrupt() {
+        panic!("park called in interrupt context");
+    }
+    if !interrupts::are_enabled() {
+        panic!("park called with interrupts disabled");
+    }
+
+    // The idle context has no task to block.
+    let Some(slot) = current_slot() else {
+        hlt();
+        return;
+    };
+    let blocked = interrupts::without_interrupts(|| {
+        // Held while the task blocks, as in `sleep_until`.
+        let mut sleepers = deadline_ns.map(|_| SLEEPERS.lock());
+        let mut scheduler = SCHEDULER.lock();
+        let Some(ctx) = scheduler.contexts[slot].as_mut() else {
+            return false;
+        };
+        if (cancellable && ctx.cancelled) || mem::take(&mut ctx.unparked) {
+            return false;
+        }
+        ctx.blocked = true;
+        ctx.wake_at = deadline_ns;
+        ctx.block_reason = reason;
+        ctx.sleeping = false;
+        drop(scheduler);
+        if let (Some(sleepers), Some(deadline_ns)) = (sleepers.as_mut(), deadline_ns) {
+            sleepers.push(Reverse((deadline_ns, slot)));
+            NEXT_WAKEUP_NS.fetch_min(deadline_ns, Ordering::AcqRel);
+        }
+        true
+    });
+    if blocked {
+        wait_while_blocked(slot);
+        if deadline_ns.is_some() {
+            remove_sleeper(slot);
+        }
+    }
 }
 
-impl Thread {
-    pub fn new(entry: fn(u16), id: u16) -> Self {
-        Self {
-            entry,
-            id,
-            slot: Cell::new(None),
+// A blocked task is not queued again, so yielding switches away from it
+// until it is woken.
+fn wait_while_blocked(slot: usize) {
+    let blocked = || {
+        interrupts::without_interrupts(|| {
+            SCHEDULER.lock().contexts[slot].is_some_and(|ctx| ctx.blocked)
+        })
+    };
+    while blocked() {
+        yield_now();
+        // Nothing else could run here; the wakeup comes with an interrupt.
+        if blocked() {
+            hlt();
         }
     }
+}
 
-    pub fn start(&self) {
-        interrupts::without_interrupts(|| unsafe {
-            if self.slot.get().is_some() {
+/// Gives up the CPU to the next ready task right away, without waiting for
+/// the tick. The calling task goes to the back of its level and keeps its
+/// priority, and runs on if nothing else is ready.
+pub fn yield_now() {
+    if !percpu::is_installed() {
+        return;
+    }
+    if percpu::in_interrupt() {
+        panic!("yield_now called in interrupt context");
+    }
+    unsafe { asm!("int {vector}", vector = const YIELD_VECTOR) };
+}
+
+/// The priority the task with `slot` and `serial` runs at, including one it
+/// inherited. `None` once the task is gone.
+pub fn task_priority(slot: usize, serial: u64) -> Option<Priority> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .task_mut(slot, serial)
+            .map(|ctx| ctx.effective_priority())
+    })
+}
+
+/// Replaces a priority lent to a task by one lock, `from`, with `to`, as the
+/// waiters for a lock it holds come and go. The task runs at the best of
+/// its own priority and those lent by all its locks; a queued one moves up
+/// at once. Does nothing if the task with `serial` is gone.
+pub fn shift_inherited_priority(
+    (slot, serial): (usize, u64),
+    from: Option<Priority>,
+    to: Option<Priority>,
+) {
+    interrupts::without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let Some(ctx) = scheduler.task_mut(slot, serial) else {
+                return;
+            };
+            let old_level = ctx.level();
+            if let Some(from) = from {
+                ctx.inherited[from as usize] -= 1;
+            }
+            if let Some(to) = to {
+                ctx.inherited[to as usize] += 1;
+            }
+            if ctx.exited || !ctx.queued || ctx.level() >= old_level {
+                return;
+            }
+        }
+
+        // Run queues are locked before the task table, so the entry is
+        // looked up first. One that is stolen meanwhile from a CPU not yet
+        // searched keeps its level until it runs.
+        for cpu in (0..MAX_CPUS).filter_map(percpu::get) {
+            let mut run_queue = cpu.run_queue.lock();
+            let Some((level, index)) = run_queue.ready.find(slot) else {
+                continue;
+            };
+            let Some(new_level) = SCHEDULER
+                .lock()
+                .task_mut(slot, serial)
+                .map(|ctx| ctx.level())
+            else {
                 return;
+            };
+            if new_level < level {
+                run_queue.ready.levels[level].remove(index);
+                run_queue.ready.push(slot, new_level);
+                drop(run_queue);
+                crate::idle::kick(cpu);
             }
+            return;
+        }
+    });
+}
 
-            let cs = CS::get_reg().0 as u64;
-            let ss = SS::get_reg().0 as u64;
-            let rflags = initial_task_rflags().bits();
-            let slot = scheduler_mut()
-                .allocate_slot(self.entry, self.id, cs, ss, rflags)
-                .expect("No free task slot");
-            self.slot.set(Some(slot));
+/// Clears `blocked` and returns the level to queue the task at, if it must
+/// be queued. A task that has not been switched away from yet is still
+/// running, and is queued as well; other CPUs leave it alone until it stops
+/// running.
+fn unblock(ctx: &mut TaskContext) -> Option<usize> {
+    ctx.blocked = false;
+    ctx.wake_at = None;
+    // Waking up from a block earns back the upper level.
+    ctx.demoted = false;
+    (!ctx.queued).then(|| ctx.requeue(crate::clock::nanos()))
+}
+
+/// Wakes the task in `slot` from `park`, or makes its next `park` return
+/// at once. Given a serial, only if the task still has the slot.
+pub fn unpark_task(slot: usize, serial: Option<u64>) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(ctx) = scheduler.contexts[slot]
+            .as_mut()
+            .filter(|ctx| serial.is_none_or(|serial| ctx.serial == serial))
+        else {
+            return;
+        };
+        if !ctx.blocked {
+            ctx.unparked = true;
+            return;
+        }
+        if let Some(level) = unblock(ctx) {
+            drop(scheduler);
+            enqueue(slot, level);
+        }
+    });
+}
+
+/// Blocks the calling task for `duration`. See [`sleep_until`].
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now().saturating_add(duration));
+}
+
+/// Blocks the calling task until `deadline`. The task leaves the run queues
+/// and costs nothing until a tick finds the deadline passed. The idle
+/// context and the boot code before [`init`] have no task to block and halt
+/// in place instead.
+pub fn sleep_until(deadline: Instant) {
+    if percpu::in_interrupt() {
+        panic!("sleep called in interrupt context");
+    }
+    if !interrupts::are_enabled() {
+        panic!("sleep called with interrupts disabled");
+    }
+
+    let deadline_ns = deadline.as_nanos();
+    // An `unpark` ends the wait early; the task then goes back to sleep,
+    // unless it was asked to stop.
+    while !deadline.has_passed() && !cancellation_requested() {
+        let slot = interrupts::without_interrupts(|| {
+            let slot = percpu::current().run_queue.lock().current_task?;
+            // Held while the task blocks, so the tick cannot pop the entry
+            // before there is anything to wake.
+            let mut sleepers = SLEEPERS.lock();
+            let mut scheduler = SCHEDULER.lock();
+            let ctx = scheduler.contexts[slot].as_mut()?;
+            ctx.blocked = true;
+            ctx.wake_at = Some(deadline_ns);
+            ctx.sleeping = true;
+            drop(scheduler);
+            sleepers.push(Reverse((deadline_ns, slot)));
+            NEXT_WAKEUP_NS.fetch_min(deadline_ns, Ordering::AcqRel);
+            Some(slot)
         });
+        match slot {
+            Some(slot) => {
+                wait_while_blocked(slot);
+                remove_sleeper(slot);
+            }
+            None => hlt(),
+        }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn stop(&self) {
-        interrupts::without_interrupts(|| unsafe {
-            let Some(slot) = self.slot.replace(None) else {
-                return;
+// Drops the entry of `slot` left in `SLEEPERS` by a wakeup that came before
+// its deadline.
+fn remove_sleeper(slot: usize) {
+    interrupts::without_interrupts(|| {
+        let mut sleepers = SLEEPERS.lock();
+        let len = sleepers.len();
+        sleepers.retain(|&Reverse((_, sleeper))| sleeper != slot);
+        if sleepers.len() != len {
+            let next = sleepers
+                .peek()
+                .map_or(u64::MAX, |&Reverse((deadline, _))| deadline);
+            NEXT_WAKEUP_NS.store(next, Ordering::Release);
+        }
+    });
+}
+
+/// Whether [`Thread::stop`] was called for the calling task.
+pub fn cancellation_requested() -> bool {
+    interrupts::without_interrupts(|| {
+        let run_queue = percpu::current().run_queue.lock();
+        let Some(slot) = run_queue.current_task else {
+            return false;
+        };
+        SCHEDULER.lock().contexts[slot].is_some_and(|ctx| ctx.cancelled)
+    })
+}
+
+/// Exits the calling task with [`EXIT_STOPPED`] if it was asked to stop.
+/// Threads call it where they can end cleanly, holding no locks.
+pub fn check_cancelled() {
+    if cancellation_requested() {
+        exit(EXIT_STOPPED);
+    }
+}
+
+/// When the earliest sleeping task is due, for the idle loop.
+pub fn next_wakeup() -> Option<Instant> {
+    match NEXT_WAKEUP_NS.load(Ordering::Acquire) {
+        u64::MAX => None,
+        nanos => Some(Instant::from_nanos(nanos)),
+    }
+}
+
+/// Wakes the tasks whose sleep deadline is at or before `now`.
+fn wake_sleepers(now: u64) {
+    while now >= NEXT_WAKEUP_NS.load(Ordering::Acquire) {
+        let expired = {
+            let mut sleepers = SLEEPERS.lock();
+            let expired = match sleepers.peek() {
+                Some(&Reverse((deadline, _))) if deadline <= now => sleepers.pop(),
+                _ => None,
             };
+            let next = sleepers
+                .peek()
+                .map_or(u64::MAX, |&Reverse((deadline, _))| deadline);
+            NEXT_WAKEUP_NS.store(next, Ordering::Release);
+            expired
+        };
+        let Some(Reverse((deadline, slot))) = expired else {
+            break;
+        };
 
-            scheduler_mut().clear_slot(slot);
-        });
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(ctx) = scheduler.contexts[slot].as_mut()
+            && ctx.blocked
+            && ctx.wake_at == Some(deadline)
+            && let Some(level) = unblock(ctx)
+        {
+            drop(scheduler);
+            enqueue(slot, level);
+        }
     }
 }
 
+/// What a CPU is running, as far as can be told without waiting for a lock.
+// Fields are only read through `Debug`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum CurrentTask {
+    Idle,
+    Task {
+        slot: usize,
+        id: u16,
+        name: &'static str,
+    },
+    /// The run queue or task table is locked, possibly by the CPU itself.
+    Unknown,
+}
+
+/// The calling CPU's current task. Never spins, so it is usable from an NMI.
+pub fn current_task() -> CurrentTask {
+    let Some(run_queue) = percpu::current().run_queue.try_lock() else {
+        return CurrentTask::Unknown;
+    };
+    let Some(slot) = run_queue.current_task else {
+        return CurrentTask::Idle;
+    };
+    let Some(scheduler) = SCHEDULER.try_lock() else {
+        return CurrentTask::Unknown;
+    };
+    match scheduler.contexts[slot] {
+        Some(ctx) => CurrentTask::Task {
+            slot,
+            id: ctx.id,
+            name: ctx.name,
+        },
+        None => CurrentTask::Unknown,
+    }
+}
+
+/// A snapshot of every task, by slot, for `ps`-style listings. Each stack
+/// is scanned for its high-water mark with the task table locked, so this
+/// is for debugging rather than hot paths.
+pub fn tasks() -> Vec<TaskInfo> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        (0..scheduler.contexts.len())
+            .filter_map(|slot| scheduler.task_info(slot))
+            .collect()
+    })
+}
+
+/// Stack usage of every task that has a stack of its own.
+#[allow(dead_code)]
+pub fn stack_usage() -> Vec<StackUsage> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        (0..scheduler.stacks.len())
+            .filter_map(|slot| scheduler.stack_usage(slot))
+            .collect()
+    })
+}
+
+/// Slot of the calling CPU's task; `None` in its idle context.
+pub fn current_slot() -> Option<usize> {
+    interrupts::without_interrupts(|| percpu::current().run_queue.lock().current_task)
+}
+
+/// Slot and serial of the calling task. Unlike the slot alone, the pair
+/// still names the task once the slot is reused.
+pub fn current_task_ref() -> Option<(usize, u64)> {
+    interrupts::without_interrupts(|| {
+        let slot = percpu::current().run_queue.lock().current_task?;
+        let serial = SCHEDULER.lock().contexts[slot]?.serial;
+        Some((slot, serial))
+    })
+}
+
+/// Sets up a task with a new stack and queues it. Returns its slot and
+/// serial.
+fn start_task(start: TaskStart, options: &TaskOptions) -> (usize, u64) {
+    // Slots of exited tasks are freed before looking for one.
+    reap_exited();
+    let stack = TaskStack::new(options.stack_size, stack_guard());
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let slot = scheduler.allocate_slot(start, stack, options);
+        let slots = scheduler.contexts.len();
+        let (level, serial) =
+            scheduler.contexts[slot].map_or((0, 0), |ctx| (ctx.level(), ctx.serial));
+        drop(scheduler);
+        reserve_ready_queues(slots);
+        enqueue(slot, level);
+        (slot, serial)
+    })
+}
+
+/// Grows the ready queues of every CPU to hold `slots` entries per level.
+/// Called from task context before a new slot is first queued.
+fn reserve_ready_queues(slots: usize) {
+    // Raised first, so a CPU coming online either is seen here or sees it.
+    READY_CAPACITY.fetch_max(slots, Ordering::SeqCst);
+    for cpu in (0..MAX_CPUS).filter_map(percpu::get) {
+        cpu.run_queue.lock().ready.reserve(slots);
+    }
+}
+
+/// Why a spawned thread ended without a value: the message of its panic, or
+/// the code it called [`exit`] with.
+#[derive(Clone)]
+pub struct JoinError {
+    code: i32,
+    message: [u8; JOIN_ERROR_MESSAGE_BYTES],
+    len: u8,
+}
+
+impl JoinError {
+    fn exited(code: i32) -> Self {
+        Self {
+            code,
+            message: [0; JOIN_ERROR_MESSAGE_BYTES],
+            len: 0,
+        }
+    }
+
+    // Formats into a fixed buffer, which keeps the error a plain value.
+    fn from_panic(info: &PanicInfo<'_>) -> Self {
+        let mut error = Self::exited(EXIT_PANICKED);
+        let _ = write!(error, "{}", info.message());
+        if let Some(location) = info.location() {
+            let _ = write!(error, " at {}:{}", location.file(), location.line());
+        }
+        error
+    }
+
+    /// Exit code of the thread: [`EXIT_PANICKED`] after a panic.
+    #[allow(dead_code)]
+    pub fn exit_code(&self) -> i32 {
+        self.code
+    }
+
+    /// The panic message, empty if the thread exited.
+    pub fn message(&self) -> &str {
+        // `write_str` only cuts at character boundaries.
+        core::str::from_utf8(&self.message[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl Write for JoinError {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let len = self.len as usize;
+        let mut end = text.len().min(JOIN_ERROR_MESSAGE_BYTES - len);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.message[len..len + end].copy_from_slice(&text.as_bytes()[..end]);
+        self.len += end as u8;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinError")
+            .field("code", &self.code)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            EXIT_PANICKED => write!(f, "thread panicked: {}", self.message()),
+            code => write!(f, "thread exited with code {}", code),
+        }
+    }
+}
+
+// Lets the panic handler and `exit` fail a packet without knowing its result
+// type. Failing a packet that is already complete does nothing.
+trait JoinPacket: Send + Sync {
+    fn fail(&self, error: JoinError);
+}
+
+/// Result slot shared by a spawned thread and its `JoinHandle`.
+struct Packet<T> {
+    result: spin::Mutex<Option<Result<T, JoinError>>>,
+    done: AtomicBool,
+    // Slot and serial of the task blocked in `join`; the serial keeps a
+    // late `complete` from waking whatever task took the slot over.
+    waiter: spin::Mutex<Option<(usize, u64)>>,
+}
+
+impl<T> Packet<T> {
+    fn complete(&self, result: Result<T, JoinError>) {
+        *self.result.lock() = Some(result);
+        self.done.store(true, Ordering::SeqCst);
+        let waiter = *self.waiter.lock();
+        if let Some((slot, serial)) = waiter {
+            unpark_task(slot, Some(serial));
+        }
+    }
+}
+
+impl<T: Send> JoinPacket for Packet<T> {
+    fn fail(&self, error: JoinError) {
+        // Only the thread itself completes its packet, so this cannot race.
+        if !self.done.load(Ordering::SeqCst) {
+            self.complete(Err(error));
+        }
+    }
+}
+
+// The packet as seen by the spawned closure. A panic abandons the closure's
+// frame, so an `Arc` kept there would never be dropped; the task's
+// `TaskStart` holds the reference that keeps the packet alive instead.
+struct PacketRef<T>(*const Packet<T>);
+
+unsafe impl<T: Send> Send for PacketRef<T> {}
+
+impl<T> PacketRef<T> {
+    fn get(&self) -> &Packet<T> {
+        unsafe { &*self.0 }
+    }
+}
+
+/// Owned permission to wait for a spawned thread. Dropping it detaches the
+/// thread.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Blocks until the thread finishes and returns its value, or why it
+    /// ended without one.
+    #[allow(dead_code)]
+    pub fn join(self) -> Result<T, JoinError> {
+        if let Some(task) = current_task_ref() {
+            *self.packet.waiter.lock() = Some(task);
+        }
+        while !self.packet.done.load(Ordering::SeqCst) {
+            park_uncancellable(JOIN_REASON, None);
+        }
+        self.packet
+            .result
+            .lock()
+            .take()
+            .expect("finished thread left no result")
+    }
+
+    #[allow(dead_code)]
+    pub fn is_finished(&self) -> bool {
+        self.packet.done.load(Ordering::Acquire)
+    }
+}
+
+/// Runs `main` on a new thread of normal priority. Its panics end only the
+/// thread and come back from [`JoinHandle::join`] as a [`JoinError`].
+#[allow(dead_code)]
+pub fn spawn<F, T>(main: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_named("spawned", main)
+}
+
+/// Like [`spawn`], with the name shown in task listings.
+#[allow(dead_code)]
+pub fn spawn_named<F, T>(name: &'static str, main: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: spin::Mutex::new(None),
+        done: AtomicBool::new(false),
+        waiter: spin::Mutex::new(None),
+    });
+    let their_packet = PacketRef(Arc::as_ptr(&packet));
+    let start = TaskStart {
+        entry: Some(TaskEntry::Closure(Box::new(move || {
+            their_packet.get().complete(Ok(main()));
+        }))),
+        join: Some(packet.clone()),
+    };
+    start_task(
+        start,
+        &TaskOptions {
+            id: 0,
+            name,
+            priority: Priority::Normal,
+            time_slice_ns: DEFAULT_TIME_SLICE.as_nanos() as u64,
+            stack_size: DEFAULT_STACK_SIZE,
+        },
+    );
+    JoinHandle { packet }
+}
+
+/// Ends the calling task after a panic if it is a spawned one, reporting
+/// the panic to its `JoinHandle`. Returns when the panic cannot be contained
+/// to the task: in interrupt context, or with interrupts disabled, when a
+/// scheduler lock may be held. The task's stack is abandoned as it is, so
+/// other locks it held stay locked.
+pub fn exit_after_panic(info: &PanicInfo<'_>) {
+    if !percpu::is_installed() || percpu::in_interrupt() || !interrupts::are_enabled() {
+        return;
+    }
+    let join = interrupts::without_interrupts(|| {
+        let run_queue = percpu::current().run_queue.try_lock()?;
+        let slot = run_queue.current_task?;
+        SCHEDULER.try_lock()?.starts[slot].as_ref()?.join.clone()
+    });
+    let Some(join) = join else {
+        return;
+    };
+
+    join.fail(JoinError::from_panic(info));
+    drop(join);
+    exit_current_task(EXIT_PANICKED);
+}
+
+/// Builds a saved context at the top of a fresh stack that starts `entry`
+/// when switched to, and returns its address.
+fn init_entry_frame(top: usize, entry: extern "C" fn() -> !) -> usize {
+    let cs = CS::get_reg().0 as u64;
+    let ss = SS::get_reg().0 as u64;
+    let rflags = initial_task_rflags().bits();
+    let stack_top = top & !0xF;
+
+    // Reserve 24 bytes so task entry starts with SysV 16-byte alignment expectations.
+    // The first 16 bytes also serve as optional iret RSP/SS slots when needed.
+    let task_rsp = stack_top - TASK_ENTRY_STACK_RESERVE_QWORDS * mem::size_of::<u64>();
+    unsafe {
+        let stack_slots = task_rsp as *mut u64;
+        ptr::write(stack_slots, task_rsp as u64);
+        ptr::write(stack_slots.add(1), ss);
+        ptr::write(stack_slots.add(2), 0);
+    }
+
+    let context_ptr = task_rsp - mem::size_of::<SavedContext>();
+    let context = context_ptr as *mut SavedContext;
+
+    unsafe {
+        ptr::write_bytes(context as *mut u8, 0, mem::size_of::<SavedContext>());
+        (*context).rip = entry as *const () as usize as u64;
+        (*context).cs = cs;
+        (*context).rflags = rflags;
+    }
+
+    context_ptr
+}
+
 fn initial_task_rflags() -> RFlags {
     const RESERVED_BIT_1: u64 = 1 << 1;
     RFlags::from_bits_retain(RESERVED_BIT_1 | RFlags::INTERRUPT_FLAG.bits())
 }
 
+/// Hands a task to the CPU with the shortest run queue, waking it if it
+/// halts with its tick stopped.
+fn enqueue(slot: usize, level: usize) {
+    let target = (0..MAX_CPUS)
+        .filter_map(percpu::get)
+        .min_by_key(|cpu| cpu.run_queue.lock().ready.len())
+        .unwrap_or_else(percpu::current);
+    target.run_queue.lock().ready.push(slot, level);
+    crate::idle::kick(target);
+}
+
+/// Whether any CPU has a task waiting in its run queue. Idle CPUs keep their
+/// tick while this holds, so they can steal the waiting tasks.
+pub fn tasks_waiting() -> bool {
+    interrupts::without_interrupts(|| {
+        (0..MAX_CPUS)
+            .filter_map(percpu::get)
+            .any(|cpu| !cpu.run_queue.lock().ready.is_empty())
+    })
+}
+
 extern "C" fn task_entry_trampoline() -> ! {
-    let task = interrupts::without_interrupts(|| unsafe { scheduler_ref().current_task_start() });
-    let Some(task) = task else {
-        exit_current_task();
-    };
+    let task = interrupts::without_interrupts(|| {
+        let run_queue = percpu::current().run_queue.lock();
+        SCHEDULER.lock().take_current_entry(&run_queue)
+    });
+    match task {
+        Some((TaskEntry::Function(entry), id)) => entry(id),
+        Some((TaskEntry::Closure(main), _)) => main(),
+        None => {}
+    }
+    exit_current_task(0);
+}
 
-    (task.entry)(task.id);
-    exit_current_task();
+/// Ends the calling task with `code`, which task listings show until its
+/// slot is reaped. Returning from a task's entry exits with 0. A spawned
+/// thread's `JoinHandle::join` returns a [`JoinError`] with the code.
+#[allow(dead_code)]
+pub fn exit(code: i32) -> ! {
+    if percpu::in_interrupt() {
+        panic!("exit called in interrupt context");
+    }
+    exit_current_task(code);
 }
 
-fn exit_current_task() -> ! {
-    interrupts::without_interrupts(|| unsafe {
-        scheduler_mut().exit_current_task();
+fn exit_current_task(code: i32) -> ! {
+    let start = interrupts::without_interrupts(|| {
+        let run_queue = percpu::current().run_queue.lock();
+        let slot = run_queue.current_task?;
+        SCHEDULER.lock().exit_slot(slot, code)
     });
+    if let Some(join) = start.and_then(|start| start.join) {
+        join.fail(JoinError::exited(code));
+    }
 
+    // Only comes back while nothing else can run on this CPU.
     loop {
+        yield_now();
         hlt();
     }
 }
 
-pub fn init(timer_interval_ms: f64) {
-    unsafe {
-        scheduler_mut().reset();
+/// Starts preemptive scheduling with a tick every `timer_interval_us`.
+///
+/// The calibrated local APIC timer drives the tick when available; the PIT is
+/// only used as a fallback. The calling code becomes the boot task in slot
+/// 0, which may [`exit`] once it is done; the CPU then runs its idle
+/// context whenever no task is ready.
+pub fn init(timer_interval_us: u64) {
+    let idle_stack = TaskStack::new(IDLE_STACK_SIZE, stack_guard());
+    interrupts::without_interrupts(|| {
+        let cpu = percpu::current();
+        let mut run_queue = cpu.run_queue.lock();
+        *run_queue = RunQueue::new();
+        run_queue.current_task = Some(0);
+        run_queue.idle_rsp = init_entry_frame(idle_stack.top(), idle_entry);
+        run_queue.idle_stack = Some(idle_stack);
+        run_queue.slice_start_ns = crate::clock::nanos();
+        run_queue.slice_irq_ns = cpu.stats.irq_ns.load(Ordering::Relaxed);
+
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.reset();
+        cpu.set_fpu_area(scheduler.init_fpu_area(0));
+        let slots = scheduler.contexts.len();
+        drop(scheduler);
+        drop(run_queue);
+        reserve_ready_queues(slots);
+
+        SLEEPERS.lock().clear();
+        NEXT_WAKEUP_NS.store(u64::MAX, Ordering::Release);
+    });
+    TICK_INTERVAL_US.store(timer_interval_us, Ordering::Release);
+
+    if crate::lapic::is_enabled() {
+        crate::lapic::start_timer(crate::lapic::preferred_timer_mode(), timer_interval_us);
+    } else {
+        crate::pit::start(0, timer_interval_us);
     }
+}
 
-    crate::pit::start(0, timer_interval_ms);
+pub fn tick_interval_us() -> u64 {
+    TICK_INTERVAL_US.load(Ordering::Acquire)
+}
+
+/// Starts the scheduler tick on an application processor. Until it picks up
+/// a task, the CPU runs the context that called this as its idle context.
+pub fn init_ap() {
+    interrupts::without_interrupts(|| {
+        let slots = READY_CAPACITY.load(Ordering::SeqCst);
+        percpu::current().run_queue.lock().ready.reserve(slots);
+    });
+    crate::lapic::start_timer(
+        crate::lapic::preferred_timer_mode(),
+        TICK_INTERVAL_US.load(Ordering::Acquire),
+    );
 }
 
 unsafe extern "C" {
     fn timer_interrupt_handler();
+    fn lapic_timer_interrupt_handler();
+    fn yield_interrupt_handler();
 }
 
 pub fn timer_interrupt_handler_addr() -> u64 {
     timer_interrupt_handler as *const () as usize as u64
 }
 
+pub fn lapic_timer_interrupt_handler_addr() -> u64 {
+    lapic_timer_interrupt_handler as *const () as usize as u64
+}
+
+pub fn yield_interrupt_handler_addr() -> u64 {
+    yield_interrupt_handler as *const () as usize as u64
+}
+
+/// Picks the task to run next on `cpu`. A `yielding` task is switched away
+/// from even if its slice has time left.
+fn on_timer_interrupt(cpu: &PerCpu, current_rsp: usize, yielding: bool) -> usize {
+    let mut run_queue = cpu.run_queue.lock();
+    let mut scheduler = SCHEDULER.lock();
+
+    let now = crate::clock::nanos();
+    let irq_ns = cpu.stats.irq_ns.load(Ordering::Relaxed);
+    let slice_ns = now
+        .saturating_sub(run_queue.slice_start_ns)
+        .saturating_sub(irq_ns.saturating_sub(run_queue.slice_irq_ns));
+    run_queue.slice_start_ns = now;
+    run_queue.slice_irq_ns = irq_ns;
+
+    let current = run_queue.current_task;
+    let starving = scheduler.starving_level(&run_queue.ready, now).is_some();
+    let mut keep_running = false;
+    match current {
+        Some(slot) => {
+            let current_ready = scheduler.is_valid_saved_rsp(slot, current_rsp);
+            if let Some(ctx) = scheduler.contexts[slot].as_mut() {
+                ctx.saved_rsp = current_rsp;
+                ctx.ready = current_ready;
+                ctx.runtime_ns = ctx.runtime_ns.saturating_add(slice_ns);
+                ctx.slice_used_ns = ctx.slice_used_ns.saturating_add(slice_ns);
+                if current_ready && !ctx.queued && !ctx.blocked && !ctx.exited {
+                    let expired = ctx.slice_used_ns >= ctx.slice_ns();
+                    keep_running = !yielding
+                        && !expired
+                        && !starving
+                        && !run_queue.ready.has_above(ctx.level());
+                    if !keep_running {
+                        ctx.demoted |= expired;
+                        // Still marked running, so no other CPU takes it
+                        // before `finish_switch`.
+                        let level = ctx.requeue(now);
+                        run_queue.ready.push(slot, level);
+                    }
+                }
+            }
+        }
+        None => run_queue.idle_rsp = current_rsp,
+    }
+    let time = if current.is_some() {
+        &cpu.stats.task_ns
+    } else {
+        &cpu.stats.idle_ns
+    };
+    time.fetch_add(slice_ns, Ordering::Relaxed);
+
+    let next = if keep_running {
+        current
+    } else {
+        scheduler
+            .pop_ready(&mut run_queue.ready, current, now)
+            .or_else(|| scheduler.steal(cpu.cpu_index()))
+    };
+
+    if next.is_some() && next == current {
+        // Only counts as scheduling when nothing else is waiting for the CPU.
+        if run_queue.ready.is_empty() {
+            crate::watchdog::touch_scheduler();
+        }
+        return current_rsp;
+    }
+    crate::watchdog::touch_scheduler();
+
+    if let Some(slot) = current
+        && let Some(stack) = scheduler.stacks[slot].as_ref()
+        && !stack.canary_intact()
+    {
+        panic!("stack overflow in task {}", slot);
+    }
+
+    let previous = current.filter(|&prev| scheduler.contexts[prev].is_some());
+    if let Some(slot) = next
+        && let Some(ctx) = scheduler.contexts[slot].as_mut()
+    {
+        ctx.running = true;
+        ctx.switches += 1;
+        let saved_rsp = ctx.saved_rsp;
+        run_queue.previous_task = previous;
+        run_queue.current_task = Some(slot);
+        cpu.set_fpu_area(scheduler.fpu_areas[slot]);
+        // Slot 0 runs on the boot stack and never enters from ring 3.
+        let stack = scheduler.stacks[slot].as_ref();
+        if let Some(stack) = stack {
+            cpu.set_kernel_stack((stack.top() & !0xF) as u64);
+        }
+        GUARD_PAGE
+            .get()
+            .store(stack.map_or(0, TaskStack::guard_page), Ordering::Relaxed);
+        GUARD_SLOT.get().store(slot, Ordering::Relaxed);
+        cpu.stats.context_switches.fetch_add(1, Ordering::Relaxed);
+        return saved_rsp;
+    }
+
+    // Nothing is ready: fall back to the idle context.
+    let idle_rsp = run_queue.idle_rsp;
+    if current.is_some() && idle_rsp != 0 {
+        run_queue.previous_task = previous;
+        run_queue.current_task = None;
+        cpu.set_fpu_area(0);
+        GUARD_PAGE.get().store(0, Ordering::Relaxed);
+        return idle_rsp;
+    }
+
+    current_rsp
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn timer_interrupt_dispatch(context_ptr: *mut SavedContext) -> *mut SavedContext {
-    let current_rsp = context_ptr as usize;
-    let next_rsp = unsafe { scheduler_mut().on_timer_interrupt(current_rsp) };
+extern "C" fn timer_interrupt_dispatch(
+    context_ptr: *mut SavedContext,
+    source: u64,
+) -> *mut SavedContext {
+    let vector = match source {
+        TICK_SOURCE_LAPIC => crate::lapic::TIMER_VECTOR,
+        TICK_SOURCE_YIELD => YIELD_VECTOR,
+        _ => crate::pic::PIC_1_OFFSET,
+    };
+    percpu::irq_enter(vector);
+    let cpu = percpu::current();
+    // Not a tick: time and timers are left to the next real one.
+    if source == TICK_SOURCE_YIELD {
+        return on_timer_interrupt(cpu, context_ptr as usize, true) as *mut SavedContext;
+    }
+    cpu.stats.ticks.fetch_add(1, Ordering::Relaxed);
+    crate::watchdog::on_tick(cpu, unsafe { &*context_ptr });
+    wake_sleepers(crate::clock::nanos());
+    crate::timer::on_tick();
+    let next_rsp = on_timer_interrupt(cpu, context_ptr as usize, false);
 
-    crate::pic::send_eoi(crate::pic::PIC_1_OFFSET);
+    match source {
+        TICK_SOURCE_LAPIC => {
+            crate::lapic::on_timer_interrupt();
+            // A one-shot from the idle loop; the CPU may be switching to a
+            // task and never get back there to restart the tick.
+            crate::idle::exit_tickless();
+            crate::lapic::eoi();
+        }
+        TICK_SOURCE_PIT => crate::pic::send_eoi(crate::pic::PIC_1_OFFSET),
+        _ => unreachable!("unknown tick source {}", source),
+    }
     next_rsp as *mut SavedContext
 }
+
+/// Runs on the next task's stack; releases the task switched away from.
+#[unsafe(no_mangle)]
+extern "C" fn timer_interrupt_finish() {
+    let mut run_queue = percpu::current().run_queue.lock();
+    let mut exited = false;
+    if let Some(slot) = run_queue.previous_task.take()
+        && let Some(ctx) = SCHEDULER.lock().contexts[slot].as_mut()
+    {
+        ctx.running = false;
+        exited = ctx.exited;
+    }
+    drop(run_queue);
+    // The stack it ran on can go now; freeing it is left to a worker.
+    if exited && !REAP_QUEUED.swap(true, Ordering::AcqRel) && !crate::workqueue::queue_call(reap, 0)
+    {
+        REAP_QUEUED.store(false, Ordering::Release);
+    }
+    percpu::irq_exit();
+}
+
+fn reap(_: usize) {
+    REAP_QUEUED.store(false, Ordering::Release);
+    reap_exited();
+}
+
+/// Frees the stacks and slots of exited tasks that no CPU runs any more.
+fn reap_exited() {
+    while let Some(stack) = interrupts::without_interrupts(|| SCHEDULER.lock().take_exited()) {
+        drop(stack);
+    }
+}
+
+// Entry of the bootstrap CPU's idle context.
+extern "C" fn idle_entry() -> ! {
+    crate::idle::run();
+}
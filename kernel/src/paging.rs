@@ -1,12 +1,14 @@
+use alloc::boxed::Box;
 use core::ptr::{addr_of, addr_of_mut};
 use spin::Mutex;
-use x86_64::PhysAddr;
-use x86_64::instructions::interrupts;
+use x86_64::instructions::{interrupts, tlb};
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::registers::model_specific::Msr;
 use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
 
+pub const PAGE_SIZE: u64 = 4096;
 const HUGE_2MIB: u64 = 2 * 1024 * 1024;
 const ENTRIES_PER_TABLE: usize = 512;
 const ADDRESS_SPACE_LIMIT: u64 = 512 * 1024 * 1024 * 1024;
@@ -24,6 +26,10 @@ unsafe fn set_pat_wc_slot4() {
     const IA32_PAT: u32 = 0x277;
     const PAT_WC: u64 = 0x01;
 
+    if !crate::cpu::has(crate::cpu::Feature::Pat) {
+        return;
+    }
+
     let mut msr = Msr::new(IA32_PAT);
     let mut pat = unsafe { msr.read() };
     pat &= !(0xff_u64 << 32); // slot4 clear
@@ -103,6 +109,30 @@ impl PML4 {
         self.map(virt_block, phys_block, merged_flags);
     }
 
+    /// The 4 KiB entry mapping `addr`. The 2 MiB block around it is split
+    /// into a page table on first use, with the same identity mapping.
+    fn page_entry_mut(&mut self, addr: u64) -> &mut PageTableEntry {
+        let entry = self.pd_entry_mut(addr / HUGE_2MIB);
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // Only plain memory is split, so bit 12 is no PAT selector here.
+            let base = entry.addr().as_u64();
+            let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+            let mut table = Box::new(PageTable::new());
+            for (index, page) in table.iter_mut().enumerate() {
+                page.set_addr(PhysAddr::new(base + index as u64 * PAGE_SIZE), flags);
+            }
+            // Never freed; the heap is identity mapped, so its address is
+            // also the physical one.
+            let table = Box::leak(table) as *mut PageTable as u64;
+            entry.set_addr(
+                PhysAddr::new(table),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+        }
+        let table = unsafe { &mut *(entry.addr().as_u64() as *mut PageTable) };
+        &mut table[(addr % HUGE_2MIB / PAGE_SIZE) as usize]
+    }
+
     pub unsafe fn load(&self) {
         let pml4_phys = PhysAddr::new(addr_of!(self.pml4) as u64);
         let pml4_frame = PhysFrame::containing_address(pml4_phys);
@@ -113,6 +143,50 @@ impl PML4 {
     }
 }
 
+/// Physical address of the active PML4, for handing to application processors.
+pub fn kernel_pml4_addr() -> u64 {
+    Cr3::read().0.start_address().as_u64()
+}
+
+/// Marks the 2 MiB blocks covering a device register window as uncached.
+pub fn map_mmio(phys_addr: u64, size: u64) {
+    let end_addr = phys_addr
+        .checked_add(size.saturating_sub(1))
+        .expect("MMIO end address overflow");
+    let start_block = phys_addr / HUGE_2MIB;
+    let end_block = end_addr / HUGE_2MIB;
+    let uncached = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+    interrupts::without_interrupts(|| {
+        let mut pml4 = KERNEL_PML4.lock();
+        for block_index in start_block..=end_block {
+            pml4.add_flags(block_index, uncached);
+        }
+    });
+    tlb::flush_all();
+}
+
+/// Unmaps the 4 KiB page at `addr` so any access to it faults, or maps it
+/// back. Used for guard pages below task stacks.
+pub fn set_guard_page(addr: u64, guard: bool) {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        panic!("guard page address {:#x} is not page aligned", addr);
+    }
+    interrupts::without_interrupts(|| {
+        let mut pml4 = KERNEL_PML4.lock();
+        let entry = pml4.page_entry_mut(addr);
+        let mut flags = entry.flags();
+        flags.set(PageTableFlags::PRESENT, !guard);
+        let frame = entry.addr();
+        entry.set_addr(frame, flags);
+    });
+    tlb::flush(VirtAddr::new(addr));
+    // Missing pages are never cached, so only unmapping concerns others.
+    if guard {
+        crate::lapic::broadcast_tlb_flush();
+    }
+}
+
 pub fn init() {
     unsafe {
         set_pat_wc_slot4();
@@ -124,3 +198,11 @@ pub fn init() {
         });
     }
 }
+
+/// The page tables are shared and loaded by the AP trampoline; only the PAT
+/// MSR has to be programmed on every CPU.
+pub fn init_ap() {
+    unsafe {
+        set_pat_wc_slot4();
+    }
+}
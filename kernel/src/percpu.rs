@@ -0,0 +1,245 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+
+use crate::multitask::RunQueue;
+use crate::smp::MAX_CPUS;
+
+pub struct CpuStats {
+    pub ticks: AtomicU64,
+    pub interrupts: AtomicU64,
+    pub context_switches: AtomicU64,
+    // Time spent in interrupt handlers and bottom halves, and the rest split
+    // between tasks and the idle context.
+    pub irq_ns: AtomicU64,
+    pub task_ns: AtomicU64,
+    pub idle_ns: AtomicU64,
+    // Times the idle loop stopped the periodic tick, and for how long.
+    pub tickless_entries: AtomicU64,
+    pub tickless_ns: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            context_switches: AtomicU64::new(0),
+            irq_ns: AtomicU64::new(0),
+            task_ns: AtomicU64::new(0),
+            idle_ns: AtomicU64::new(0),
+            tickless_entries: AtomicU64::new(0),
+            tickless_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Data owned by one CPU, reachable through its GS base.
+#[repr(C)]
+pub struct PerCpu {
+    // Must stay first: `gs:[0]` turns the GS base back into a pointer.
+    self_ptr: AtomicU64,
+    cpu_index: usize,
+    apic_id: AtomicU32,
+    irq_depth: AtomicUsize,
+    irq_start_ns: AtomicU64,
+    // Extended state save area of the running task; read by the timer entry.
+    fpu_area: AtomicU64,
+    // Kernel stack of the running task and the user stack pointer parked by
+    // the SYSCALL entry while it runs on it.
+    kernel_stack: AtomicU64,
+    user_rsp: AtomicU64,
+    pub run_queue: Mutex<RunQueue>,
+    pub stats: CpuStats,
+}
+
+const _: [(); 0] = [(); mem::offset_of!(PerCpu, self_ptr)];
+
+pub const FPU_AREA_OFFSET: usize = mem::offset_of!(PerCpu, fpu_area);
+pub const KERNEL_STACK_OFFSET: usize = mem::offset_of!(PerCpu, kernel_stack);
+pub const USER_RSP_OFFSET: usize = mem::offset_of!(PerCpu, user_rsp);
+
+impl PerCpu {
+    const fn new(cpu_index: usize) -> Self {
+        Self {
+            self_ptr: AtomicU64::new(0),
+            cpu_index,
+            apic_id: AtomicU32::new(0),
+            irq_depth: AtomicUsize::new(0),
+            irq_start_ns: AtomicU64::new(0),
+            fpu_area: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            run_queue: Mutex::new(RunQueue::new()),
+            stats: CpuStats::new(),
+        }
+    }
+
+    pub fn cpu_index(&self) -> usize {
+        self.cpu_index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Records the local APIC ID other CPUs address this one by.
+    pub fn set_apic_id(&self, apic_id: u32) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    /// Sets where the timer entry saves and restores extended register
+    /// state; 0 for a context without its own area.
+    pub fn set_fpu_area(&self, area: usize) {
+        self.fpu_area.store(area as u64, Ordering::Relaxed);
+    }
+
+    /// Sets the stack used by system calls and interrupts from ring 3. Only
+    /// valid on the calling CPU's own block.
+    pub fn set_kernel_stack(&self, stack_top: u64) {
+        self.kernel_stack.store(stack_top, Ordering::Relaxed);
+        crate::gdt::set_kernel_stack(stack_top);
+    }
+}
+
+static BSP_BLOCK: PerCpu = PerCpu::new(0);
+static BLOCKS: [AtomicPtr<PerCpu>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Points the bootstrap processor's GS base at its per-CPU block. Must run
+/// after `gdt::init`, since loading the GS selector clears the base.
+pub fn init() {
+    install(&BSP_BLOCK);
+}
+
+/// Allocates and installs the per-CPU block of an application processor.
+pub fn init_ap(cpu_index: usize) {
+    if cpu_index == 0 || cpu_index >= MAX_CPUS {
+        panic!("invalid application processor index {}", cpu_index);
+    }
+    install(Box::leak(Box::new(PerCpu::new(cpu_index))));
+}
+
+fn install(block: &'static PerCpu) {
+    let addr = block as *const PerCpu as u64;
+    block.self_ptr.store(addr, Ordering::Release);
+
+    // The kernel always runs with GS base pointing at the block. Entry paths
+    // from ring 3 must `swapgs` on the way in and out, which exchanges it with
+    // the user value parked in IA32_KERNEL_GS_BASE.
+    GsBase::write(VirtAddr::new(addr));
+    KernelGsBase::write(VirtAddr::new(0));
+
+    BLOCKS[block.cpu_index].store(addr as *mut PerCpu, Ordering::Release);
+}
+
+/// The calling CPU's block. Only valid after [`init`] or [`init_ap`].
+pub fn current() -> &'static PerCpu {
+    let addr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) addr, options(nostack, readonly, preserves_flags));
+        &*(addr as *const PerCpu)
+    }
+}
+
+/// Index of the calling CPU, read with a single GS-relative load.
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{offset}]",
+            out(reg) index,
+            offset = const mem::offset_of!(PerCpu, cpu_index),
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    index
+}
+
+/// Block of CPU `cpu_index`, if that CPU has been brought up.
+pub fn get(cpu_index: usize) -> Option<&'static PerCpu> {
+    let block = BLOCKS.get(cpu_index)?.load(Ordering::Acquire);
+    unsafe { block.as_ref() }
+}
+
+/// Marks the start of the handler for `vector` on the calling CPU.
+pub fn irq_enter(vector: u8) {
+    let cpu = current();
+    if cpu.irq_depth.fetch_add(1, Ordering::Relaxed) == 0 {
+        cpu.irq_start_ns
+            .store(crate::clock::nanos(), Ordering::Relaxed);
+    }
+    cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+    crate::stats::count_vector(vector);
+}
+
+/// Marks the end of an interrupt handler. Leaving the outermost one drains
+/// the CPU's bottom halves, which still count as interrupt context and
+/// interrupt time.
+pub fn irq_exit() {
+    let cpu = current();
+    if cpu.irq_depth.load(Ordering::Relaxed) == 1 {
+        crate::softirq::run_pending();
+        let start = cpu.irq_start_ns.load(Ordering::Relaxed);
+        let elapsed = crate::clock::nanos().saturating_sub(start);
+        cpu.stats.irq_ns.fetch_add(elapsed, Ordering::Relaxed);
+    }
+    cpu.irq_depth.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Whether the calling CPU's GS base points at its block yet. Only needed on
+/// paths that can run before [`init`], such as exception handlers.
+pub fn is_installed() -> bool {
+    !GsBase::read().is_null()
+}
+
+/// Whether the calling CPU is running an interrupt handler or bottom half.
+pub fn in_interrupt() -> bool {
+    current().irq_depth.load(Ordering::Relaxed) != 0
+}
+
+/// A variable with one instance per CPU, declared with [`percpu!`].
+pub struct PerCpuVar<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpuVar<T> {
+    #[doc(hidden)]
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// The calling CPU's instance.
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_index()]
+    }
+
+    pub fn get_for(&self, cpu_index: usize) -> &T {
+        &self.slots[cpu_index]
+    }
+}
+
+/// Declares statics with one instance per CPU:
+///
+/// ```ignore
+/// percpu! {
+///     static COUNTER: AtomicU64 = AtomicU64::new(0);
+/// }
+/// COUNTER.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpuVar<$ty> =
+                $crate::percpu::PerCpuVar::new([const { $init }; $crate::smp::MAX_CPUS]);
+        )*
+    };
+}
+
+pub(crate) use percpu;
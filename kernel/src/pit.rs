@@ -1,46 +1,110 @@
+use core::hint::spin_loop;
 use x86_64::instructions::{interrupts, port::Port};
 
 const MAX_CHANNEL: u8 = 2;
-const MAX_INTERVAL_MS: f64 = 54.0;
+const MAX_INTERVAL_US: u64 = 54_925;
 
 const COMMAND_PORT: u16 = 0x43;
 const DATA_PORT_BASE: u16 = 0x40;
+const SPEAKER_PORT: u16 = 0x61;
 
 const CHANNEL_SHIFT: u8 = 6;
+const MODE_ONE_SHOT: u8 = 0b0011_0000;
 const MODE_RATE_GENERATOR: u8 = 0b0011_0100;
-const BASE_FREQUENCY_HZ: u32 = 1_193_182;
+const BASE_FREQUENCY_HZ: u64 = 1_193_182;
 
-fn divisor_from_millis(milliseconds: f64) -> u16 {
-    let divisor = ((BASE_FREQUENCY_HZ as f64) * milliseconds / 1000.0) as u16;
+const SPEAKER_GATE2: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const SPEAKER_OUT2: u8 = 1 << 5;
+
+fn divisor_from_micros(microseconds: u64) -> u16 {
+    let divisor = BASE_FREQUENCY_HZ * microseconds / 1_000_000;
     if divisor == 0 {
         panic!("PIT divisor must be non-zero");
     }
-    divisor
+    divisor.min(u16::MAX as u64) as u16
 }
 
-pub fn start(pit_number: u8, milliseconds: f64) {
-    if pit_number > MAX_CHANNEL {
-        panic!("PIT number must be 0, 1, or 2");
-    }
-
-    if !(0.0..=MAX_INTERVAL_MS).contains(&milliseconds) || milliseconds == 0.0 {
-        panic!("milliseconds must satisfy 0 < ms <= 54");
+fn check_interval(microseconds: u64) {
+    if microseconds == 0 || microseconds > MAX_INTERVAL_US {
+        panic!("microseconds must satisfy 0 < us <= {}", MAX_INTERVAL_US);
     }
+}
 
-    interrupts::without_interrupts(|| unsafe {
+fn program_channel(pit_number: u8, mode: u8, divisor: u16) {
+    unsafe {
         let mut command_port = Port::new(COMMAND_PORT);
         let mut data_port = Port::new(DATA_PORT_BASE + pit_number as u16);
         let channel_bits = pit_number << CHANNEL_SHIFT;
 
-        // Channel + lobyte/hibyte + mode2(rate generator) + binary counter.
-        command_port.write(channel_bits | MODE_RATE_GENERATOR);
-
-        let divisor = divisor_from_millis(milliseconds);
+        // Channel + lobyte/hibyte + mode + binary counter.
+        command_port.write(channel_bits | mode);
         data_port.write((divisor & 0xFF) as u8);
         data_port.write((divisor >> 8) as u8);
+    }
+}
+
+pub fn start(pit_number: u8, microseconds: u64) {
+    if pit_number > MAX_CHANNEL {
+        panic!("PIT number must be 0, 1, or 2");
+    }
+    check_interval(microseconds);
+
+    interrupts::without_interrupts(|| {
+        program_channel(
+            pit_number,
+            MODE_RATE_GENERATOR,
+            divisor_from_micros(microseconds),
+        );
     });
 
     if pit_number == 0 {
         crate::pic::enable_irq(0);
     }
 }
+
+/// Busy-waits on PIT channel 2 without using interrupts.
+///
+/// This is only meant for calibrating other timers during early boot.
+pub fn busy_wait_us(microseconds: u64) {
+    interrupts::without_interrupts(|| {
+        start_countdown(microseconds);
+        while !countdown_elapsed() {
+            spin_loop();
+        }
+        stop_countdown();
+    });
+}
+
+/// Starts a one-shot countdown on PIT channel 2, which is gated through the
+/// speaker port so its OUT pin can be polled with [`countdown_elapsed`].
+pub fn start_countdown(microseconds: u64) {
+    check_interval(microseconds);
+
+    interrupts::without_interrupts(|| unsafe {
+        let mut speaker_port: Port<u8> = Port::new(SPEAKER_PORT);
+
+        // Gate off while loading the counter, speaker output disabled.
+        let speaker = speaker_port.read() & !(SPEAKER_GATE2 | SPEAKER_DATA);
+        speaker_port.write(speaker);
+
+        program_channel(2, MODE_ONE_SHOT, divisor_from_micros(microseconds));
+
+        // Raising the gate starts the countdown.
+        speaker_port.write(speaker | SPEAKER_GATE2);
+    });
+}
+
+/// Whether the channel 2 countdown has reached terminal count.
+pub fn countdown_elapsed() -> bool {
+    let mut speaker_port: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe { speaker_port.read() & SPEAKER_OUT2 != 0 }
+}
+
+pub fn stop_countdown() {
+    let mut speaker_port: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe {
+        let speaker = speaker_port.read() & !(SPEAKER_GATE2 | SPEAKER_DATA);
+        speaker_port.write(speaker);
+    }
+}
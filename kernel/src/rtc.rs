@@ -1,36 +1,64 @@
 use core::hint::spin_loop;
 use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::instructions::{hlt, interrupts, port::Port};
 
+use crate::time::{self, DateTime, Duration, Instant};
+
 const CMOS_INDEX_PORT: u16 = 0x70;
 const CMOS_DATA_PORT: u16 = 0x71;
 const NMI_DISABLE: u8 = 0x80;
 
+const RTC_REG_SECONDS: u8 = 0x00;
+const RTC_REG_MINUTES: u8 = 0x02;
+const RTC_REG_HOURS: u8 = 0x04;
+const RTC_REG_DAY: u8 = 0x07;
+const RTC_REG_MONTH: u8 = 0x08;
+const RTC_REG_YEAR: u8 = 0x09;
 const RTC_REG_A: u8 = 0x0A;
 const RTC_REG_B: u8 = 0x0B;
 const RTC_REG_C: u8 = 0x0C;
+const RTC_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RTC_24_HOUR: u8 = 1 << 1;
+const RTC_BINARY: u8 = 1 << 2;
 const RTC_PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+const RTC_HOUR_PM: u8 = 1 << 7;
+// Assumed when the FADT names no century register.
+const DEFAULT_CENTURY: u16 = 20;
 const RTC_RATE_1024_HZ: u8 = 6;
-const RTC_TICKS_PER_SEC: u64 = 1024;
+pub const TICKS_PER_SEC: u64 = 1024;
+// An update cycle takes about 2 ms, so a longer one means a broken RTC.
+const UPDATE_TIMEOUT: Duration = Duration::from_millis(10);
+// Two agreeing readings normally take two attempts, three across an update.
+const MAX_READ_ATTEMPTS: usize = 5;
 
 static RTC_TICKS: AtomicU64 = AtomicU64::new(0);
+// Selecting a register and accessing it must not be split by another CPU or
+// by the RTC interrupt.
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
 
 fn cmos_read(reg: u8) -> u8 {
-    unsafe {
-        let mut index_port: Port<u8> = Port::new(CMOS_INDEX_PORT);
-        let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
-        index_port.write(NMI_DISABLE | reg);
-        data_port.read()
-    }
+    interrupts::without_interrupts(|| {
+        let _guard = CMOS_LOCK.lock();
+        unsafe {
+            let mut index_port: Port<u8> = Port::new(CMOS_INDEX_PORT);
+            let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
+            index_port.write(NMI_DISABLE | reg);
+            data_port.read()
+        }
+    })
 }
 
 fn cmos_write(reg: u8, value: u8) {
-    unsafe {
-        let mut index_port: Port<u8> = Port::new(CMOS_INDEX_PORT);
-        let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
-        index_port.write(NMI_DISABLE | reg);
-        data_port.write(value);
-    }
+    interrupts::without_interrupts(|| {
+        let _guard = CMOS_LOCK.lock();
+        unsafe {
+            let mut index_port: Port<u8> = Port::new(CMOS_INDEX_PORT);
+            let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
+            index_port.write(NMI_DISABLE | reg);
+            data_port.write(value);
+        }
+    })
 }
 
 pub fn init() {
@@ -49,32 +77,128 @@ pub fn init() {
     crate::pic::enable_irq(8);
 }
 
+/// Turns the 1024 Hz interrupt off again once a better clock source makes
+/// its ticks unnecessary.
+pub fn stop_periodic_interrupt() {
+    crate::pic::disable_irq(8);
+    interrupts::without_interrupts(|| {
+        let prev_b = cmos_read(RTC_REG_B);
+        cmos_write(RTC_REG_B, prev_b & !RTC_PERIODIC_INTERRUPT_ENABLE);
+        let _ = cmos_read(RTC_REG_C);
+    });
+}
+
 pub fn on_interrupt() {
     RTC_TICKS.fetch_add(1, Ordering::Release);
     // Must read register C to acknowledge and re-arm RTC interrupts.
     let _ = cmos_read(RTC_REG_C);
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawDate {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw_date(century_register: Option<u8>) -> Option<RawDate> {
+    time::poll_until(UPDATE_TIMEOUT, || {
+        (cmos_read(RTC_REG_A) & RTC_UPDATE_IN_PROGRESS == 0).then_some(())
+    })
+    .ok()?;
+    Some(RawDate {
+        second: cmos_read(RTC_REG_SECONDS),
+        minute: cmos_read(RTC_REG_MINUTES),
+        hour: cmos_read(RTC_REG_HOURS),
+        day: cmos_read(RTC_REG_DAY),
+        month: cmos_read(RTC_REG_MONTH),
+        year: cmos_read(RTC_REG_YEAR),
+        century: century_register.map_or(0, cmos_read),
+    })
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Reads the date and time kept by the CMOS RTC, which is assumed to run
+/// on UTC. Readings are repeated until two in a row agree, so an update
+/// between the individual registers cannot produce a torn value. Returns
+/// `None` if the RTC stays mid-update or never reads the same twice.
+pub fn read_datetime() -> Option<DateTime> {
+    let century_register = crate::acpi::rtc_century_register();
+    let mut previous = read_raw_date(century_register)?;
+    let mut agreed = None;
+    for _ in 1..MAX_READ_ATTEMPTS {
+        let again = read_raw_date(century_register)?;
+        if again == previous {
+            agreed = Some(again);
+            break;
+        }
+        previous = again;
+    }
+    let raw = agreed?;
+
+    let status_b = cmos_read(RTC_REG_B);
+    let decode = |value: u8| {
+        if status_b & RTC_BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    // In 12-hour mode the PM flag sits on top of the hour, in either format.
+    let mut hour = decode(raw.hour & !RTC_HOUR_PM);
+    if status_b & RTC_24_HOUR == 0 {
+        hour %= 12;
+        if raw.hour & RTC_HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let century = match century_register {
+        Some(_) => decode(raw.century) as u16,
+        None => DEFAULT_CENTURY,
+    };
+
+    Some(DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    })
+}
+
+pub fn ticks() -> u64 {
+    RTC_TICKS.load(Ordering::Acquire)
+}
+
 pub fn sleep(milliseconds: u64) {
     if milliseconds == 0 {
         return;
     }
 
-    let ticks_needed = (milliseconds.saturating_mul(RTC_TICKS_PER_SEC) + 999) / 1000;
-    let ticks_needed = core::cmp::max(1, ticks_needed);
-    let target = RTC_TICKS
-        .load(Ordering::Acquire)
-        .saturating_add(ticks_needed);
-
-    let restore_disabled = !interrupts::are_enabled();
-    while RTC_TICKS.load(Ordering::Acquire) < target {
-        if restore_disabled {
-            interrupts::enable();
-            hlt();
-            interrupts::disable();
-        } else {
-            hlt();
-        }
+    let duration = Duration::from_millis(milliseconds);
+    if interrupts::are_enabled() {
+        crate::multitask::sleep(duration);
+        return;
+    }
+
+    // Callers with interrupts disabled halt in place and keep the CPU. The
+    // deadline comes from the best monotonic clock (HPET or invariant TSC
+    // when present); RTC ticks only serve as the fallback clock source.
+    let deadline = Instant::now().saturating_add(duration);
+    while !deadline.has_passed() {
+        interrupts::enable();
+        hlt();
+        interrupts::disable();
         spin_loop();
     }
 }
@@ -0,0 +1,228 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::debug;
+
+pub const MAX_CPUS: usize = 64;
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+const TRAMPOLINE_MAX_ADDR: u64 = 0x10_0000;
+const INIT_DELAY_US: u64 = 10_000;
+const STARTUP_DELAY_US: u64 = 200;
+const STARTUP_TIMEOUT_US: u64 = 100_000;
+const ONLINE_TIMEOUT_US: u64 = 1_000_000;
+const POLL_INTERVAL_US: u64 = 100;
+// The trampoline loads CR3 while still in 32-bit mode.
+const TRAMPOLINE_MAX_CR3: u64 = u32::MAX as u64;
+// Values of `AP_EXPECTED` besides the CPU index being offered.
+const NO_AP: usize = usize::MAX;
+const AP_CLAIMED: usize = usize::MAX - 1;
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+// Index offered to the AP being started. The AP swaps in `AP_CLAIMED` on
+// entry; one that finds anything else started after the BSP gave up on it.
+static AP_EXPECTED: AtomicUsize = AtomicUsize::new(NO_AP);
+static AP_ONLINE: AtomicBool = AtomicBool::new(false);
+
+/// How far an AP got before `start_ap` stopped waiting for it.
+enum ApStart {
+    /// Never ran; its CPU index is still free.
+    Failed,
+    /// Took its CPU index but did not come online in time.
+    Late,
+    Online,
+}
+
+// Must match the parameter block at `ap_trampoline_params` in asmtools.rs.
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64,
+}
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Number of CPUs that finished initialization, including the bootstrap CPU.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Index of the calling CPU; the bootstrap processor is 0.
+#[allow(dead_code)]
+pub fn current_cpu() -> usize {
+    crate::percpu::cpu_index()
+}
+
+/// Starts every application processor listed in the MADT with the
+/// INIT-SIPI-SIPI sequence. `trampoline_addr` is a page below 1 MiB reserved
+/// by the bootloader. Returns the number of online CPUs.
+pub fn init(trampoline_addr: u64) -> usize {
+    if !crate::lapic::is_enabled() {
+        return cpu_count();
+    }
+
+    let bsp_apic_id = crate::lapic::id();
+
+    if trampoline_addr == 0
+        || trampoline_addr >= TRAMPOLINE_MAX_ADDR
+        || trampoline_addr & 0xFFF != 0
+    {
+        debug::println!("SMP: no usable AP trampoline page.");
+        return cpu_count();
+    }
+    let cr3 = crate::paging::kernel_pml4_addr();
+    if cr3 > TRAMPOLINE_MAX_CR3 {
+        debug::println!(
+            "SMP: PML4 at {:#x} is out of reach of the AP trampoline.",
+            cr3
+        );
+        return cpu_count();
+    }
+
+    let params = install_trampoline(trampoline_addr);
+    let vector_page = (trampoline_addr >> 12) as u8;
+
+    // Not `cpu_count`: an AP that is late coming online keeps its index.
+    let mut cpu_index = cpu_count();
+    for apic_id in crate::acpi::processor_apic_ids() {
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+
+        if cpu_index >= MAX_CPUS {
+            debug::println!("SMP: more than {} CPUs, ignoring the rest.", MAX_CPUS);
+            break;
+        }
+
+        match start_ap(apic_id, cpu_index, vector_page, params, cr3) {
+            ApStart::Online => cpu_index += 1,
+            ApStart::Late => {
+                debug::println!("SMP: CPU with APIC ID {} is late coming online.", apic_id);
+                cpu_index += 1;
+            }
+            ApStart::Failed => {
+                debug::println!("SMP: CPU with APIC ID {} did not start.", apic_id);
+            }
+        }
+    }
+
+    cpu_count()
+}
+
+fn install_trampoline(trampoline_addr: u64) -> *mut TrampolineParams {
+    unsafe {
+        let start = ptr::addr_of!(ap_trampoline_start) as usize;
+        let params = ptr::addr_of!(ap_trampoline_params) as usize;
+        let end = ptr::addr_of!(ap_trampoline_end) as usize;
+
+        ptr::copy_nonoverlapping(start as *const u8, trampoline_addr as *mut u8, end - start);
+        (trampoline_addr as usize + (params - start)) as *mut TrampolineParams
+    }
+}
+
+fn start_ap(
+    apic_id: u32,
+    cpu_index: usize,
+    vector_page: u8,
+    params: *mut TrampolineParams,
+    cr3: u64,
+) -> ApStart {
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+
+    AP_ONLINE.store(false, Ordering::Release);
+    AP_EXPECTED.store(cpu_index, Ordering::Release);
+    unsafe {
+        ptr::write_volatile(
+            params,
+            TrampolineParams {
+                cr3,
+                stack_top,
+                entry: ap_entry as *const () as usize as u64,
+                cpu_index: cpu_index as u64,
+            },
+        );
+    }
+
+    crate::lapic::send_init(apic_id);
+    crate::clock::busy_wait_us(INIT_DELAY_US);
+    crate::lapic::send_startup(apic_id, vector_page);
+    crate::clock::busy_wait_us(STARTUP_DELAY_US);
+    let claimed = || AP_EXPECTED.load(Ordering::Acquire) == AP_CLAIMED;
+    if !claimed() {
+        crate::lapic::send_startup(apic_id, vector_page);
+    }
+
+    // Withdrawing the offer fails if the AP took it after all.
+    if !wait_for(claimed, STARTUP_TIMEOUT_US)
+        && AP_EXPECTED
+            .compare_exchange(cpu_index, NO_AP, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    {
+        return ApStart::Failed;
+    }
+    if wait_for(|| AP_ONLINE.load(Ordering::Acquire), ONLINE_TIMEOUT_US) {
+        ApStart::Online
+    } else {
+        ApStart::Late
+    }
+}
+
+fn wait_for(done: impl Fn() -> bool, timeout_us: u64) -> bool {
+    let mut waited_us = 0;
+    while !done() {
+        if waited_us >= timeout_us {
+            return false;
+        }
+        crate::clock::busy_wait_us(POLL_INTERVAL_US);
+        waited_us += POLL_INTERVAL_US;
+    }
+    true
+}
+
+extern "C" fn ap_entry(cpu_index: u64) -> ! {
+    if AP_EXPECTED
+        .compare_exchange(
+            cpu_index as usize,
+            AP_CLAIMED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        // The BSP gave up on this CPU and may have handed its index on.
+        loop {
+            interrupts::disable();
+            hlt();
+        }
+    }
+
+    crate::gdt::init_ap(cpu_index as usize);
+    crate::percpu::init_ap(cpu_index as usize);
+    crate::cpu::enable_features();
+    crate::fpu::init_ap();
+    crate::idt::init();
+    crate::paging::init_ap();
+    crate::lapic::init_ap();
+    crate::syscall::init();
+
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+    debug::println!("CPU {} online (APIC ID {}).", cpu_index, crate::lapic::id());
+    AP_ONLINE.store(true, Ordering::Release);
+
+    crate::multitask::init_ap();
+    crate::watchdog::init_ap();
+    interrupts::enable();
+
+    // Becomes the CPU's idle context once the scheduler ticks.
+    crate::idle::run();
+}
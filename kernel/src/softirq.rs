@@ -0,0 +1,73 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::percpu::percpu;
+
+const QUEUE_LEN: usize = 64;
+
+/// Deferred half of an interrupt handler, called with the argument it was
+/// raised with.
+pub type BottomHalf = fn(usize);
+
+/// Fixed-size ring, so raising never touches the heap: running out of memory
+/// in an interrupt handler panics with no task to contain it to.
+struct PendingQueue {
+    entries: [Option<(BottomHalf, usize)>; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl PendingQueue {
+    const fn new() -> Self {
+        Self {
+            entries: [None; QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, entry: (BottomHalf, usize)) -> bool {
+        if self.len == QUEUE_LEN {
+            return false;
+        }
+        self.entries[(self.head + self.len) % QUEUE_LEN] = Some(entry);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<(BottomHalf, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.entries[self.head].take();
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        entry
+    }
+}
+
+percpu! {
+    static PENDING: Mutex<PendingQueue> = Mutex::new(PendingQueue::new());
+}
+
+/// Queues `func(arg)` to run on the calling CPU when its outermost interrupt
+/// handler returns. Returns false if the CPU's queue is full.
+pub fn raise(func: BottomHalf, arg: usize) -> bool {
+    interrupts::without_interrupts(|| PENDING.get().lock().push((func, arg)))
+}
+
+/// Runs the bottom halves pending on the calling CPU. Called from
+/// [`percpu::irq_exit`] after the handler has sent its EOI, still with
+/// interrupts disabled, so bottom halves must not block or allocate; heavier
+/// work goes to the [`workqueue`](crate::workqueue). Entries raised while
+/// draining wait for the next interrupt exit.
+pub fn run_pending() {
+    let queue = PENDING.get();
+    let count = queue.lock().len;
+    for _ in 0..count {
+        let Some((func, arg)) = queue.lock().pop() else {
+            break;
+        };
+        func(arg);
+    }
+}
@@ -0,0 +1,198 @@
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::debug;
+use crate::percpu::{self, percpu};
+use crate::smp::MAX_CPUS;
+
+const VECTORS: usize = 256;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "#DE divide error",
+    "#DB debug",
+    "NMI",
+    "#BP breakpoint",
+    "#OF overflow",
+    "#BR bound range",
+    "#UD invalid opcode",
+    "#NM device not available",
+    "#DF double fault",
+    "coprocessor segment overrun",
+    "#TS invalid TSS",
+    "#NP segment not present",
+    "#SS stack fault",
+    "#GP general protection",
+    "#PF page fault",
+    "reserved",
+    "#MF x87 error",
+    "#AC alignment check",
+    "#MC machine check",
+    "#XM SIMD error",
+    "#VE virtualization",
+    "#CP control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "#HV hypervisor injection",
+    "#VC VMM communication",
+    "#SX security",
+    "reserved",
+];
+
+// Label, description and value of a summary row below the vector counts.
+type SummaryRow = (&'static str, &'static str, fn(&CpuTimes) -> u64);
+
+percpu! {
+    static VECTOR_COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+}
+
+/// Time and switch totals of one CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuTimes {
+    pub irq_ns: u64,
+    pub task_ns: u64,
+    pub idle_ns: u64,
+    pub context_switches: u64,
+    pub tickless_entries: u64,
+    pub tickless_ns: u64,
+}
+
+impl CpuTimes {
+    /// Share of the accounted time the CPU was not idle, in percent.
+    pub fn busy_percent(&self) -> u64 {
+        let busy = self.irq_ns + self.task_ns;
+        match busy + self.idle_ns {
+            0 => 0,
+            total => busy * 100 / total,
+        }
+    }
+}
+
+/// Counts one delivery of `vector` on the calling CPU.
+pub fn count_vector(vector: u8) {
+    VECTOR_COUNTS.get()[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often `vector` has fired on CPU `cpu_index`.
+pub fn vector_count(cpu_index: usize, vector: u8) -> u64 {
+    VECTOR_COUNTS.get_for(cpu_index)[vector as usize].load(Ordering::Relaxed)
+}
+
+pub fn cpu_times(cpu_index: usize) -> Option<CpuTimes> {
+    let stats = &percpu::get(cpu_index)?.stats;
+    Some(CpuTimes {
+        irq_ns: stats.irq_ns.load(Ordering::Relaxed),
+        task_ns: stats.task_ns.load(Ordering::Relaxed),
+        idle_ns: stats.idle_ns.load(Ordering::Relaxed),
+        context_switches: stats.context_switches.load(Ordering::Relaxed),
+        tickless_entries: stats.tickless_entries.load(Ordering::Relaxed),
+        tickless_ns: stats.tickless_ns.load(Ordering::Relaxed),
+    })
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0..32 => EXCEPTION_NAMES[vector as usize],
+        crate::pic::PIC_1_OFFSET => "PIT timer",
+        crate::pic::PIC_2_OFFSET => "RTC",
+        crate::keyboard::INTERRUPT_VECTOR => "keyboard",
+        crate::lapic::TIMER_VECTOR => "LAPIC timer",
+        crate::lapic::WAKEUP_VECTOR => "wakeup IPI",
+        crate::lapic::TLB_FLUSH_VECTOR => "TLB flush IPI",
+        crate::multitask::YIELD_VECTOR => "yield",
+        crate::lapic::SPURIOUS_VECTOR => "spurious",
+        _ => "",
+    }
+}
+
+/// Prints a table in the spirit of /proc/interrupts: one row per vector that
+/// has fired, one column per online CPU, followed by the time split.
+#[allow(dead_code)]
+pub fn print_interrupts() {
+    let cpus = || (0..MAX_CPUS).filter(|&cpu| percpu::get(cpu).is_some());
+
+    let mut line = String::from("     ");
+    for cpu in cpus() {
+        let _ = write!(line, " {:>10}", alloc::format!("CPU{}", cpu));
+    }
+    debug::println!("{}", line);
+
+    for vector in 0..=u8::MAX {
+        if cpus().all(|cpu| vector_count(cpu, vector) == 0) {
+            continue;
+        }
+        line.clear();
+        let _ = write!(line, "{:>4}:", vector);
+        for cpu in cpus() {
+            let _ = write!(line, " {:>10}", vector_count(cpu, vector));
+        }
+        let _ = write!(line, "  {}", vector_name(vector));
+        debug::println!("{}", line);
+    }
+
+    let rows: [SummaryRow; 7] = [
+        ("CSW", "context switches", |times| times.context_switches),
+        ("IRQ", "ms in interrupts", |times| times.irq_ns / 1_000_000),
+        ("TSK", "ms in tasks", |times| times.task_ns / 1_000_000),
+        ("IDL", "ms idle", |times| times.idle_ns / 1_000_000),
+        ("UTL", "% busy", CpuTimes::busy_percent),
+        ("NHZ", "tickless idle entries", |times| {
+            times.tickless_entries
+        }),
+        ("NHT", "ms with the tick stopped", |times| {
+            times.tickless_ns / 1_000_000
+        }),
+    ];
+    for (label, description, value) in rows {
+        line.clear();
+        let _ = write!(line, "{:>4}:", label);
+        for times in cpus().filter_map(cpu_times) {
+            let _ = write!(line, " {:>10}", value(&times));
+        }
+        let _ = write!(line, "  {}", description);
+        debug::println!("{}", line);
+    }
+}
+
+/// Prints a `ps`-style listing of every task.
+pub fn print_tasks() {
+    debug::println!(
+        "{:>4} {:>5} {:<12} {:<22} {:<8} {:>9} {:>9} {:>12}",
+        "SLOT",
+        "ID",
+        "NAME",
+        "STATE",
+        "PRIO",
+        "CPU ms",
+        "SWITCHES",
+        "STACK"
+    );
+    let mut state = String::new();
+    let mut priority = String::new();
+    let mut stack = String::new();
+    for task in crate::multitask::tasks() {
+        state.clear();
+        let _ = write!(state, "{}", task.state);
+        priority.clear();
+        let _ = write!(priority, "{:?}", task.priority);
+        stack.clear();
+        if let Some(usage) = task.stack {
+            let _ = write!(stack, "{}/{}", usage.high_water, usage.size);
+        }
+        debug::println!(
+            "{:>4} {:>5} {:<12} {:<22} {:<8} {:>9} {:>9} {:>12}",
+            task.slot,
+            task.id,
+            task.name,
+            state,
+            priority,
+            task.cpu_time.as_millis(),
+            task.switches,
+            stack
+        );
+    }
+}
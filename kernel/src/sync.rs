@@ -0,0 +1,566 @@
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::multitask::{self, Priority};
+use crate::percpu;
+use crate::time::{Instant, TimedOut};
+
+// `RwLock` state bit set while a writer holds it; the rest counts readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// Slot and serial of the calling task, if it may park: not the idle
+// context, not an interrupt handler, and interrupts enabled.
+fn blocking_task() -> Option<(usize, u64)> {
+    if !percpu::is_installed() || percpu::in_interrupt() || !interrupts::are_enabled() {
+        return None;
+    }
+    multitask::current_task_ref()
+}
+
+/// Tasks waiting for a condition to hold, woken by whoever changes it. The
+/// other types here are built on it. A task asked to stop with
+/// `Thread::stop` keeps waiting until the condition holds.
+pub struct WaitQueue {
+    // Slots and serials of parked tasks, first come first served. Locked
+    // with interrupts disabled, and never while a scheduler lock is held.
+    waiters: spin::Mutex<VecDeque<(usize, u64)>>,
+    // Shown for the waiting tasks in task listings.
+    reason: &'static str,
+}
+
+impl WaitQueue {
+    /// A queue whose waiters are listed as blocked on `reason`.
+    pub const fn named(reason: &'static str) -> Self {
+        Self {
+            waiters: spin::Mutex::new(VecDeque::new()),
+            reason,
+        }
+    }
+
+    /// Parks the calling task until `condition` returns true. It is checked
+    /// before parking and after every wakeup, and returning true ends the
+    /// wait right away, so it may take what it waited for. Callers that
+    /// cannot park spin on `condition` instead.
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        let _ = self.wait(None, condition);
+    }
+
+    /// Like `wait_until`, but gives up once `deadline` has passed.
+    pub fn wait_until_deadline(
+        &self,
+        deadline: Instant,
+        condition: impl FnMut() -> bool,
+    ) -> Result<(), TimedOut> {
+        self.wait(Some(deadline), condition)
+    }
+
+    fn wait(
+        &self,
+        deadline: Option<Instant>,
+        mut condition: impl FnMut() -> bool,
+    ) -> Result<(), TimedOut> {
+        loop {
+            if condition() {
+                return Ok(());
+            }
+            if deadline.is_some_and(|deadline| deadline.has_passed()) {
+                return Err(TimedOut);
+            }
+            let Some(task) = blocking_task() else {
+                spin_loop();
+                continue;
+            };
+
+            interrupts::without_interrupts(|| self.waiters.lock().push_back(task));
+            // A wake after the slot is queued but before `park` leaves a
+            // token that makes `park` return, so none is lost.
+            let done = condition();
+            if !done {
+                multitask::park_uncancellable(self.reason, deadline);
+            }
+            self.remove(task);
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    fn remove(&self, task: (usize, u64)) {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if let Some(index) = waiters.iter().position(|&waiter| waiter == task) {
+                waiters.remove(index);
+            }
+        });
+    }
+
+    // The best priority among the waiting tasks. Entries are read one at a
+    // time, so the scheduler is not asked while the queue is locked.
+    fn best_priority(&self) -> Option<Priority> {
+        let mut best = None;
+        for index in 0.. {
+            let waiter = interrupts::without_interrupts(|| self.waiters.lock().get(index).copied());
+            let Some((slot, serial)) = waiter else {
+                break;
+            };
+            best = best
+                .into_iter()
+                .chain(multitask::task_priority(slot, serial))
+                .min();
+        }
+        best
+    }
+
+    /// Wakes the task that has waited longest. Returns false if none waits.
+    pub fn wake_one(&self) -> bool {
+        let task = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        if let Some((slot, serial)) = task {
+            multitask::unpark_task(slot, Some(serial));
+        }
+        task.is_some()
+    }
+
+    /// Wakes every waiting task and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+}
+
+/// The task holding a lock and where it took it.
+#[derive(Clone, Copy, Debug)]
+pub struct LockOwner {
+    pub slot: usize,
+    /// Only recorded in debug mode.
+    pub location: Option<&'static Location<'static>>,
+}
+
+// Holder of an exclusive lock, tracked only if priority inheritance or debug
+// mode needs it. Written by the holder, and by waiters lending it their
+// priority.
+struct Owner {
+    // Locked with interrupts disabled, before any scheduler lock.
+    holder: spin::Mutex<Holder>,
+    inherit: bool,
+    debug: bool,
+}
+
+struct Holder {
+    // Slot and serial of the holding task.
+    task: Option<(usize, u64)>,
+    location: Option<&'static Location<'static>>,
+    // What the waiters lend the holder, until it unlocks.
+    lent: Option<Priority>,
+}
+
+impl Owner {
+    const fn new() -> Self {
+        Self {
+            holder: spin::Mutex::new(Holder {
+                task: None,
+                location: None,
+                lent: None,
+            }),
+            inherit: false,
+            debug: false,
+        }
+    }
+
+    /// Records the calling task as the holder, and lends it the priority of
+    /// the tasks still waiting in `waiters`.
+    fn set(&self, location: &'static Location<'static>, waiters: &WaitQueue) {
+        if !self.inherit && !self.debug {
+            return;
+        }
+        let task = percpu::is_installed()
+            .then(multitask::current_task_ref)
+            .flatten();
+        interrupts::without_interrupts(|| {
+            let mut holder = self.holder.lock();
+            holder.task = task;
+            holder.location = self.debug.then_some(location);
+        });
+        self.lend(waiters);
+    }
+
+    /// Forgets the holder, and takes back what the waiters lent it. What
+    /// other locks it holds lent it stays.
+    fn clear(&self) {
+        interrupts::without_interrupts(|| {
+            let mut holder = self.holder.lock();
+            let task = holder.task.take();
+            holder.location = None;
+            if let (Some(task), Some(lent)) = (task, holder.lent.take()) {
+                multitask::shift_inherited_priority(task, Some(lent), None);
+            }
+        });
+    }
+
+    fn get(&self) -> Option<LockOwner> {
+        let holder = interrupts::without_interrupts(|| {
+            let holder = self.holder.lock();
+            Some((holder.task?, holder.location))
+        });
+        holder.map(|((slot, _), location)| LockOwner { slot, location })
+    }
+
+    // Lends the holder the best priority among `waiters`, if inheritance is
+    // on, replacing what they lent it before.
+    fn lend(&self, waiters: &WaitQueue) {
+        if !self.inherit {
+            return;
+        }
+        let best = waiters.best_priority();
+        interrupts::without_interrupts(|| {
+            let mut holder = self.holder.lock();
+            let Some(task) = holder.task else {
+                return;
+            };
+            if holder.lent != best {
+                multitask::shift_inherited_priority(task, holder.lent, best);
+                holder.lent = best;
+            }
+        });
+    }
+}
+
+/// A mutual exclusion lock that parks waiting tasks instead of spinning.
+///
+/// With priority inheritance, a task waiting for the lock lends its
+/// priority to the holder until it unlocks; a task holding several such
+/// locks runs at the best priority any of their waiters lends it. In debug
+/// mode the lock records where its holder locked it.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    owner: Owner,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            waiters: WaitQueue::named("mutex"),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub const fn with_priority_inheritance(mut self) -> Self {
+        self.owner.inherit = true;
+        self
+    }
+
+    pub const fn with_debug(mut self) -> Self {
+        self.owner.debug = true;
+        self
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if !self.acquire() {
+            self.waiters.wait_until(|| {
+                if self.acquire() {
+                    return true;
+                }
+                self.owner.lend(&self.waiters);
+                false
+            });
+        }
+        self.owner.set(Location::caller(), &self.waiters);
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// The holder, if priority inheritance or debug mode tracks it.
+    pub fn owner(&self) -> Option<LockOwner> {
+        self.owner.get()
+    }
+
+    fn unlock(&self) {
+        self.owner.clear();
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // Unlocking drops the priority the locking task inherited.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A reader-writer lock that parks waiting tasks. New readers hold back
+/// while a writer waits, so writers are not starved. Priority inheritance
+/// and debug mode apply to writers, as for [`Mutex`].
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    writer: Owner,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            writer: Owner::new(),
+            waiters: WaitQueue::named("rwlock"),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub const fn with_priority_inheritance(mut self) -> Self {
+        self.writer.inherit = true;
+        self
+    }
+
+    pub const fn with_debug(mut self) -> Self {
+        self.writer.debug = true;
+        self
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 || self.writers_waiting.load(Ordering::Relaxed) != 0 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut guard = None;
+        self.waiters.wait_until(|| {
+            guard = self.try_read();
+            guard.is_some()
+        });
+        guard.expect("read lock taken")
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if !self.acquire_write() {
+            self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+            self.waiters.wait_until(|| {
+                if self.acquire_write() {
+                    return true;
+                }
+                self.writer.lend(&self.waiters);
+                false
+            });
+            self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.writer.set(Location::caller(), &self.waiters);
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// The writer holding the lock, if priority inheritance or debug mode
+    /// tracks it.
+    pub fn writer(&self) -> Option<LockOwner> {
+        self.writer.get()
+    }
+
+    fn read_unlock(&self) {
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.waiters.wake_all();
+        }
+    }
+
+    fn write_unlock(&self) {
+        self.writer.clear();
+        self.state.store(0, Ordering::Release);
+        // Readers and writers wait together; whoever comes first wins.
+        self.waiters.wake_all();
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+/// A counting semaphore; `acquire` parks until a permit is free.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::named("semaphore"),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+/// A condition variable for [`Mutex`]. Waits may end without a notify, so
+/// callers check their condition in a loop or use `wait_while`.
+pub struct Condvar {
+    // Bumped by every notify; a waiter is done once it changed.
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::named("condvar"),
+        }
+    }
+
+    /// Unlocks the mutex, parks until notified and locks it again.
+    #[track_caller]
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // Read while still locked, so a notify after the unlock is seen.
+        let sequence = self.sequence.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+        drop(guard);
+        self.waiters
+            .wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+        mutex.lock()
+    }
+
+    /// Waits for as long as `condition` holds for the protected value.
+    #[track_caller]
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,179 @@
+use core::arch::asm;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+
+use crate::cpu::{self, Feature};
+use crate::debug;
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+pub const SYS_DEBUG_WRITE: u64 = 0;
+pub const SYS_CPU_ID: u64 = 1;
+pub const SYS_CLOCK_NANOS: u64 = 2;
+
+const MAX_DEBUG_WRITE: usize = 4096;
+
+#[allow(clippy::upper_case_acronyms)]
+#[repr(i64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Errno {
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Registers pushed by both entry stubs in asmtools.rs. The convention is
+/// the Linux one: number in rax, arguments in rdi, rsi, rdx, r10, r8 and r9,
+/// result in rax with errors returned as negated errno values. Vector
+/// registers are clobbered like in a C call.
+#[repr(C)]
+struct SyscallFrame {
+    number: u64,
+    args: [u64; 6],
+}
+
+/// Conversion from a raw argument register into a handler parameter.
+trait SyscallArg: Sized {
+    fn from_raw(raw: u64) -> Result<Self, Errno>;
+}
+
+impl SyscallArg for u64 {
+    fn from_raw(raw: u64) -> Result<Self, Errno> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for usize {
+    fn from_raw(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as usize)
+    }
+}
+
+impl SyscallArg for u32 {
+    fn from_raw(raw: u64) -> Result<Self, Errno> {
+        u32::try_from(raw).map_err(|_| Errno::EINVAL)
+    }
+}
+
+/// Builds `dispatch` from `number => handler(ArgType, ...)` entries. Each
+/// argument is converted with [`SyscallArg`] before the handler runs.
+macro_rules! syscall_table {
+    ($($number:ident => $handler:ident($($arg:ty),*);)*) => {
+        fn dispatch(frame: &SyscallFrame) -> SyscallResult {
+            match frame.number {
+                $($number => {
+                    let mut _args = frame.args.iter().copied();
+                    $handler($(<$arg as SyscallArg>::from_raw(_args.next().unwrap())?),*)
+                })*
+                _ => Err(Errno::ENOSYS),
+            }
+        }
+    };
+}
+
+syscall_table! {
+    SYS_DEBUG_WRITE => sys_debug_write(usize, usize);
+    SYS_CPU_ID => sys_cpu_id();
+    SYS_CLOCK_NANOS => sys_clock_nanos();
+}
+
+// There are no separate address spaces yet, so a pointer is only checked for
+// being non-null and not wrapping around.
+fn sys_debug_write(addr: usize, len: usize) -> SyscallResult {
+    if len > MAX_DEBUG_WRITE {
+        return Err(Errno::EINVAL);
+    }
+    if addr == 0 || addr.checked_add(len).is_none() {
+        return Err(Errno::EFAULT);
+    }
+
+    with_user_access(|| {
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        let text = core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL)?;
+        debug::println!("{}", text);
+        Ok(len as u64)
+    })
+}
+
+/// Lifts SMAP for the duration of `f`, which may touch user memory.
+fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = cpu::has(Feature::Smap);
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+fn sys_cpu_id() -> SyscallResult {
+    Ok(crate::smp::current_cpu() as u64)
+}
+
+fn sys_clock_nanos() -> SyscallResult {
+    Ok(crate::time::Instant::now().as_nanos())
+}
+
+unsafe extern "C" {
+    fn syscall_entry();
+    fn syscall_int80_entry();
+}
+
+pub fn int80_handler_addr() -> u64 {
+    syscall_int80_entry as *const () as usize as u64
+}
+
+/// Enables SYSCALL/SYSRET on the calling CPU. Handlers run with interrupts
+/// disabled on the task's kernel stack.
+pub fn init() {
+    let selectors = crate::gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout does not match SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as usize as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Issues a system call through the `int 0x80` gate, which also works from
+/// kernel tasks. Meant for debugging the dispatcher.
+#[allow(dead_code)]
+pub fn int80(number: u64, args: [u64; 3]) -> i64 {
+    let result: i64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") number as i64 => result,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            clobber_abi("C"),
+        );
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn syscall_dispatch(frame: &SyscallFrame) -> i64 {
+    match dispatch(frame) {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
@@ -0,0 +1,301 @@
+use core::fmt;
+use core::hint::spin_loop;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+pub use core::time::Duration;
+
+// Longest single wait on the hardware reference, within the PIT's range.
+const BUSY_WAIT_STEP_US: u64 = 10_000;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+// Unix time in nanoseconds at monotonic time zero, and the local time zone.
+static WALL_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+static UTC_OFFSET_SECS: AtomicI32 = AtomicI32::new(0);
+
+/// A reading of the monotonic clock, in nanoseconds since the clock source
+/// was selected by `clock::init`. On the RTC fallback source the clock only
+/// advances while interrupts are enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+/// Returned when a wait gives up at its timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+fn duration_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(crate::clock::nanos())
+    }
+
+    /// The instant `nanos` after the clock started.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    #[allow(dead_code)]
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_nanos(duration)).map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_nanos(duration)).map(Self)
+    }
+
+    /// Adds `duration`, clamping at the end of the clock's range.
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        Self(self.0.saturating_add(duration_nanos(duration)))
+    }
+
+    pub fn has_passed(&self) -> bool {
+        Instant::now() >= *self
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Smallest step the selected clock source can measure.
+pub fn resolution() -> Duration {
+    Duration::from_nanos(crate::clock::resolution_ns())
+}
+
+/// Calls `poll` until it returns `Some` or `timeout` has passed. Meant for
+/// drivers waiting on device status bits; `poll` runs at least once, so a
+/// zero timeout still checks the condition.
+pub fn poll_until<T>(
+    timeout: Duration,
+    mut poll: impl FnMut() -> Option<T>,
+) -> Result<T, TimedOut> {
+    // The RTC fallback clock stands still while interrupts are disabled, and
+    // there is no HPET in that mode, so time is counted in PIT countdowns of
+    // up to one step each, polling in between.
+    if crate::clock::needs_interrupts() && !interrupts::are_enabled() {
+        let mut remaining_us = u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX);
+        loop {
+            if let Some(value) = poll() {
+                return Ok(value);
+            }
+            if remaining_us == 0 {
+                return Err(TimedOut);
+            }
+            let step = remaining_us.min(BUSY_WAIT_STEP_US);
+            remaining_us -= step;
+            crate::pit::start_countdown(step);
+            let polled = loop {
+                if let Some(value) = poll() {
+                    break Some(value);
+                }
+                if crate::pit::countdown_elapsed() {
+                    break None;
+                }
+                spin_loop();
+            };
+            crate::pit::stop_countdown();
+            if let Some(value) = polled {
+                return Ok(value);
+            }
+        }
+    }
+
+    let deadline = Instant::now().saturating_add(timeout);
+    loop {
+        if let Some(value) = poll() {
+            return Ok(value);
+        }
+        if deadline.has_passed() {
+            return Err(TimedOut);
+        }
+        spin_loop();
+    }
+}
+
+/// Spins for `duration`. Also works with interrupts disabled.
+#[allow(dead_code)]
+pub fn busy_wait(duration: Duration) {
+    if crate::clock::needs_interrupts() && !interrupts::are_enabled() {
+        let mut remaining_us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        while remaining_us > 0 {
+            let step = remaining_us.min(BUSY_WAIT_STEP_US);
+            crate::clock::busy_wait_us(step);
+            remaining_us -= step;
+        }
+        return;
+    }
+
+    let deadline = Instant::now().saturating_add(duration);
+    while !deadline.has_passed() {
+        spin_loop();
+    }
+}
+
+/// A calendar date and time of day, without a time zone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, reading the date as UTC. Dates
+    /// before the epoch clamp to zero.
+    pub fn to_unix_secs(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs = days * SECS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    pub fn from_unix_secs(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let time = secs % SECS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Proleptic Gregorian conversions after Howard Hinnant's `days_from_civil`
+// and `civil_from_days`, counting days from 1970-01-01.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Starts wall-clock time from the CMOS RTC. From then on it advances with
+/// the monotonic clock, which is far more precise than the RTC's seconds.
+/// Returns false if the RTC could not be read, in which case the wall clock
+/// counts uptime from the epoch.
+pub fn init_wall_clock() -> bool {
+    let Some(now) = crate::rtc::read_datetime() else {
+        return false;
+    };
+    set_unix_time(Duration::from_secs(now.to_unix_secs()));
+    true
+}
+
+/// Time since the Unix epoch.
+pub fn unix_time() -> Duration {
+    let offset = WALL_OFFSET_NS.load(Ordering::Acquire);
+    Duration::from_nanos(offset.saturating_add(Instant::now().as_nanos()))
+}
+
+/// Sets the wall clock, for example from a network time source. Does not
+/// write the RTC.
+pub fn set_unix_time(time: Duration) {
+    let now = Instant::now().as_nanos();
+    let offset = duration_nanos(time).saturating_sub(now);
+    WALL_OFFSET_NS.store(offset, Ordering::Release);
+}
+
+pub fn now_utc() -> DateTime {
+    DateTime::from_unix_secs(unix_time().as_secs())
+}
+
+/// Local time, shifted from UTC by the offset set with [`set_utc_offset`].
+#[allow(dead_code)]
+pub fn now_local() -> DateTime {
+    let offset = UTC_OFFSET_SECS.load(Ordering::Relaxed) as i64;
+    let secs = (unix_time().as_secs() as i64).saturating_add(offset);
+    DateTime::from_unix_secs(secs.max(0) as u64)
+}
+
+/// Sets the local time zone as an offset from UTC in seconds; positive is
+/// east of Greenwich.
+#[allow(dead_code)]
+pub fn set_utc_offset(seconds: i32) {
+    UTC_OFFSET_SECS.store(seconds, Ordering::Relaxed);
+}
@@ -0,0 +1,369 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::percpu;
+use crate::time::{Duration, Instant};
+
+// One wheel tick. Timers never fire early, and usually within a tick late.
+const JIFFY_NS: u64 = 1_000_000;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+// Timers further out wait in the last slot of the top level and are filed
+// again when it cascades.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Free,
+    Pending,
+    Running,
+    /// Cancelled while pending or running; freed once nothing refers to it.
+    Cancelled,
+}
+
+struct Timer {
+    expires: u64,
+    // Zero for one-shot timers.
+    period: u64,
+    generation: u32,
+    state: State,
+    callback: Option<Callback>,
+    // Next entry in the slot, ready or free list the timer is on.
+    next: Option<usize>,
+}
+
+/// Timers hashed into `LEVELS` rings of `SLOTS` lists each; level `n` slots
+/// span `SLOTS^n` jiffies. Lists are linked through indices into `timers`,
+/// so expiring and cascading never allocate and can run in a bottom half.
+struct Wheel {
+    timers: Vec<Timer>,
+    slots: [[Option<usize>; SLOTS]; LEVELS],
+    ready_head: Option<usize>,
+    ready_tail: Option<usize>,
+    free: Option<usize>,
+    // Next jiffy to expire.
+    current: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            timers: Vec::new(),
+            slots: [[None; SLOTS]; LEVELS],
+            ready_head: None,
+            ready_tail: None,
+            free: None,
+            current: 0,
+        }
+    }
+
+    fn allocate(&mut self, callback: Callback, expires: u64, period: u64) -> (usize, u32) {
+        let index = match self.free {
+            Some(index) => {
+                self.free = self.timers[index].next;
+                index
+            }
+            None => {
+                self.timers.push(Timer {
+                    expires: 0,
+                    period: 0,
+                    generation: 0,
+                    state: State::Free,
+                    callback: None,
+                    next: None,
+                });
+                self.timers.len() - 1
+            }
+        };
+        let timer = &mut self.timers[index];
+        timer.expires = expires;
+        timer.period = period;
+        timer.state = State::Pending;
+        timer.callback = Some(callback);
+        let generation = timer.generation;
+        self.insert(index);
+        (index, generation)
+    }
+
+    fn release(&mut self, index: usize) {
+        let timer = &mut self.timers[index];
+        timer.state = State::Free;
+        timer.generation = timer.generation.wrapping_add(1);
+        timer.next = self.free;
+        self.free = Some(index);
+    }
+
+    fn insert(&mut self, index: usize) {
+        let expires = self.timers[index].expires.max(self.current);
+        let delta = (expires - self.current).min(MAX_DELTA);
+        let target = self.current + delta;
+
+        let mut level = 0;
+        while level + 1 < LEVELS && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot = ((target >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        self.timers[index].next = self.slots[level][slot];
+        self.slots[level][slot] = Some(index);
+    }
+
+    fn push_ready(&mut self, index: usize) {
+        self.timers[index].next = None;
+        match self.ready_tail {
+            Some(tail) => self.timers[tail].next = Some(index),
+            None => self.ready_head = Some(index),
+        }
+        self.ready_tail = Some(index);
+    }
+
+    fn pop_ready(&mut self) -> Option<usize> {
+        let index = self.ready_head?;
+        self.ready_head = self.timers[index].next;
+        if self.ready_head.is_none() {
+            self.ready_tail = None;
+        }
+        Some(index)
+    }
+
+    /// Moves every timer of a higher-level slot down now that it is due.
+    fn cascade(&mut self, level: usize) {
+        let slot = ((self.current >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        let mut next = self.slots[level][slot].take();
+        while let Some(index) = next {
+            next = self.timers[index].next;
+            self.insert(index);
+        }
+    }
+
+    /// Earliest jiffy at which `expire` has work: a due level-0 slot or a
+    /// cascade of a higher-level slot. `None` if the wheel is empty.
+    fn next_expiry(&self) -> Option<u64> {
+        let mut next = None;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let unit = self.current >> shift;
+            // Higher-level slots are emptied when `current` enters them, so
+            // the one at `unit` is only used again a full turn later.
+            let first = if level == 0 { 0 } else { 1 };
+            let found = (first..first + SLOTS as u64)
+                .find(|offset| self.slots[level][((unit + offset) & SLOT_MASK) as usize].is_some());
+            if let Some(offset) = found {
+                let jiffy = if level == 0 {
+                    self.current + offset
+                } else {
+                    (unit + offset) << shift
+                };
+                next = Some(next.map_or(jiffy, |next: u64| next.min(jiffy)));
+            }
+        }
+        next
+    }
+
+    /// Advances the wheel to `now`, moving expired timers to the ready list.
+    fn expire(&mut self, now: u64) {
+        while self.current <= now {
+            for level in 1..LEVELS {
+                if self.current & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 {
+                    break;
+                }
+                self.cascade(level);
+            }
+
+            let slot = (self.current & SLOT_MASK) as usize;
+            let mut next = self.slots[0][slot].take();
+            while let Some(index) = next {
+                next = self.timers[index].next;
+                match self.timers[index].state {
+                    State::Cancelled => self.release(index),
+                    _ if self.timers[index].expires > self.current => self.insert(index),
+                    _ => self.push_ready(index),
+                }
+            }
+            self.current += 1;
+
+            // Jiffies with nothing to expire or cascade are skipped, so a
+            // CPU that was idle without a tick catches up in one step.
+            let next = self.next_expiry().unwrap_or(u64::MAX);
+            if next > self.current {
+                self.current = next.min(now.saturating_add(1));
+            }
+        }
+    }
+}
+
+// Locked with interrupts disabled from task context; the expiry bottom half
+// only tries the lock and catches up on a later tick.
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+static RUN_QUEUED: AtomicBool = AtomicBool::new(false);
+
+fn jiffies_at(instant: Instant) -> u64 {
+    instant.as_nanos() / JIFFY_NS
+}
+
+fn duration_jiffies(duration: Duration) -> u64 {
+    let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    nanos.div_ceil(JIFFY_NS)
+}
+
+/// Cancels a timer. Dropping the handle leaves the timer running.
+pub struct TimerHandle {
+    index: usize,
+    generation: u32,
+}
+
+impl TimerHandle {
+    /// Stops the timer from firing again. Returns false if it had already
+    /// finished; a callback that is running right now still completes.
+    #[allow(dead_code)]
+    pub fn cancel(&self) -> bool {
+        let callback = interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            let timer = &mut wheel.timers[self.index];
+            if timer.generation != self.generation {
+                return None;
+            }
+            match timer.state {
+                State::Pending | State::Running => {
+                    timer.state = State::Cancelled;
+                    Some(timer.callback.take())
+                }
+                State::Free | State::Cancelled => None,
+            }
+        });
+        // The callback is dropped here rather than where the wheel frees the
+        // slot, which may be a bottom half.
+        callback.is_some()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_active(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let timer = &WHEEL.lock().timers[self.index];
+            timer.generation == self.generation
+                && matches!(timer.state, State::Pending | State::Running)
+        })
+    }
+}
+
+fn add(delay: Duration, period: Duration, callback: Callback) -> TimerHandle {
+    if percpu::in_interrupt() {
+        panic!("timers cannot be created in interrupt context");
+    }
+    let now = jiffies_at(Instant::now());
+    let expires = now.saturating_add(duration_jiffies(delay));
+    let period = duration_jiffies(period);
+    let (index, generation) = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        // Nothing was ever queued, so the wheel can skip the time since boot.
+        if wheel.timers.is_empty() {
+            wheel.current = now;
+        }
+        wheel.allocate(callback, expires, period)
+    });
+    TimerHandle { index, generation }
+}
+
+/// Runs `callback` once, `delay` from now, on a workqueue thread.
+#[allow(dead_code)]
+pub fn after(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    let mut callback = Some(callback);
+    add(
+        delay,
+        Duration::ZERO,
+        Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        }),
+    )
+}
+
+/// Runs `callback` every `period` on a workqueue thread, first after one
+/// period. Runs that fall behind are skipped rather than batched.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerHandle {
+    if period.is_zero() {
+        panic!("timer period must be non-zero");
+    }
+    add(period, period, Box::new(callback))
+}
+
+/// When the next timer is due, as far as the idle loop needs to know. May be
+/// earlier than any timer, never later.
+pub fn next_deadline() -> Option<Instant> {
+    let next = interrupts::without_interrupts(|| WHEEL.lock().next_expiry())?;
+    Some(Instant::from_nanos(next.saturating_mul(JIFFY_NS)))
+}
+
+/// Called from every scheduler tick.
+pub fn on_tick() {
+    crate::softirq::raise(expire, 0);
+}
+
+fn expire(_: usize) {
+    let Some(mut wheel) = WHEEL.try_lock() else {
+        return;
+    };
+    wheel.expire(jiffies_at(Instant::now()));
+    let ready = wheel.ready_head.is_some();
+    drop(wheel);
+
+    if ready
+        && !RUN_QUEUED.swap(true, Ordering::AcqRel)
+        && !crate::workqueue::queue_call(run_ready, 0)
+    {
+        RUN_QUEUED.store(false, Ordering::Release);
+    }
+}
+
+/// Runs expired callbacks on a worker, where they may block and allocate.
+fn run_ready(_: usize) {
+    RUN_QUEUED.store(false, Ordering::Release);
+    loop {
+        let next = interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            loop {
+                let index = wheel.pop_ready()?;
+                let timer = &mut wheel.timers[index];
+                if timer.state == State::Pending
+                    && let Some(callback) = timer.callback.take()
+                {
+                    timer.state = State::Running;
+                    return Some((index, callback));
+                }
+                wheel.release(index);
+            }
+        });
+        let Some((index, mut callback)) = next else {
+            return;
+        };
+
+        callback();
+
+        // A finished callback is handed back and dropped outside the lock.
+        let finished = interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            let now = jiffies_at(Instant::now());
+            let timer = &mut wheel.timers[index];
+            if timer.state != State::Running || timer.period == 0 {
+                wheel.release(index);
+                return Some(callback);
+            }
+            timer.state = State::Pending;
+            timer.callback = Some(callback);
+            while timer.expires <= now {
+                timer.expires += timer.period;
+            }
+            wheel.insert(index);
+            None
+        });
+        drop(finished);
+    }
+}
@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+const CALIBRATION_US: u64 = 10_000;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+#[inline(always)]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+pub fn init() {
+    let hz = interrupts::without_interrupts(|| {
+        let start = read();
+        crate::clock::busy_wait_us(CALIBRATION_US);
+        let elapsed = read().wrapping_sub(start);
+        elapsed * 1_000_000 / CALIBRATION_US
+    });
+    TSC_HZ.store(hz, Ordering::Release);
+}
+
+pub fn frequency_hz() -> u64 {
+    TSC_HZ.load(Ordering::Acquire)
+}
+
+/// Converts a duration into TSC cycles, or `None` before calibration.
+pub fn micros_to_cycles(microseconds: u64) -> Option<u64> {
+    let hz = frequency_hz();
+    if hz == 0 {
+        return None;
+    }
+    Some(((hz as u128 * microseconds as u128) / 1_000_000) as u64)
+}
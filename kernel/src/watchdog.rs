@@ -0,0 +1,320 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use x86_64::registers::model_specific::{GsBase, Msr};
+
+use crate::cpu;
+use crate::debug;
+use crate::multitask::SavedContext;
+use crate::percpu::{self, PerCpu, percpu};
+use crate::smp::MAX_CPUS;
+
+const DEFAULT_THRESHOLD_SECS: u64 = 10;
+const NMI_VECTOR: u8 = 2;
+// Interval of HPET NMIs.
+const HPET_NMI_PERIOD_NS: u64 = 1_000_000_000;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+const EVTSEL_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+// Writes to IA32_PMCx sign-extend bit 31, which caps one counter period.
+const MAX_PERIOD_CYCLES: u64 = (1 << 31) - 1;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NmiSource {
+    /// Hard lockups go unnoticed; soft lockups are still reported.
+    None = 0,
+    /// Unhalted core cycle counter overflow on every CPU.
+    PerfCounter = 1,
+    /// NMI IPIs sent by another CPU that notices the stall from its tick.
+    PeerIpi = 2,
+    /// An HPET comparator delivering NMIs to the bootstrap CPU, which checks
+    /// itself and sends NMI IPIs to stalled peers.
+    Hpet = 3,
+}
+
+impl NmiSource {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::PerfCounter,
+            2 => Self::PeerIpi,
+            3 => Self::Hpet,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Returned by [`set_threshold_secs`] for a threshold of zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZeroThreshold;
+
+#[derive(Clone, Copy, Debug)]
+enum Lockup {
+    Soft,
+    Hard,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SOURCE: AtomicU8 = AtomicU8::new(NmiSource::None as u8);
+static THRESHOLD_NS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_SECS * 1_000_000_000);
+static PERIOD_CYCLES: AtomicU64 = AtomicU64::new(0);
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+static PERFMON_VERSION: AtomicU8 = AtomicU8::new(0);
+
+percpu! {
+    // Last timer tick, and the last tick that found the CPU free to run
+    // whatever was waiting for it.
+    static LAST_TICK_NS: AtomicU64 = AtomicU64::new(0);
+    static LAST_SCHEDULE_NS: AtomicU64 = AtomicU64::new(0);
+    // Reports are printed once per stall and re-armed by progress.
+    static SOFT_REPORTED: AtomicBool = AtomicBool::new(false);
+    static HARD_REPORTED: AtomicBool = AtomicBool::new(false);
+    // Set by the CPU that sends a `PeerIpi` NMI.
+    static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Starts lockup detection on the bootstrap processor. A CPU that takes no
+/// timer tick for the threshold is in a hard lockup; one whose tick keeps
+/// finding other tasks waiting without switching to them is in a soft lockup.
+///
+/// Hard lockups can only be caught with an NMI: the local APIC's performance
+/// counter interrupt where the CPU has architectural performance monitoring,
+/// else an HPET comparator with FSB delivery, else an NMI IPI from another
+/// CPU. With none of them, [`source`] reports `None`. Requires the heap and
+/// the scheduler tick.
+pub fn init() {
+    let source = if !crate::lapic::is_enabled() {
+        NmiSource::None
+    } else if let Some(perfmon) = cpu::perfmon() {
+        let width = (perfmon.counter_bits as u32).clamp(32, 64);
+        PERFMON_VERSION.store(perfmon.version, Ordering::Release);
+        COUNTER_MASK.store(u64::MAX >> (64 - width), Ordering::Release);
+        // Roughly a second of busy CPU time per NMI.
+        let period = crate::tsc::frequency_hz().clamp(1, MAX_PERIOD_CYCLES);
+        PERIOD_CYCLES.store(period, Ordering::Release);
+        NmiSource::PerfCounter
+    } else if crate::hpet::start_nmi(crate::lapic::id(), HPET_NMI_PERIOD_NS) {
+        NmiSource::Hpet
+    } else if crate::acpi::processor_apic_ids().len() > 1 {
+        NmiSource::PeerIpi
+    } else {
+        NmiSource::None
+    };
+    SOURCE.store(source as u8, Ordering::Release);
+    ENABLED.store(true, Ordering::Release);
+    start_local();
+}
+
+/// Starts lockup detection on an application processor, once its scheduler
+/// tick runs.
+pub fn init_ap() {
+    if ENABLED.load(Ordering::Acquire) {
+        start_local();
+    }
+}
+
+fn start_local() {
+    let now = crate::clock::nanos();
+    LAST_TICK_NS.get().store(now, Ordering::Relaxed);
+    LAST_SCHEDULE_NS.get().store(now, Ordering::Relaxed);
+    if source() == NmiSource::PerfCounter {
+        arm_counter();
+    }
+}
+
+pub fn source() -> NmiSource {
+    NmiSource::from_u8(SOURCE.load(Ordering::Acquire))
+}
+
+pub fn threshold_secs() -> u64 {
+    THRESHOLD_NS.load(Ordering::Relaxed) / 1_000_000_000
+}
+
+#[allow(dead_code)]
+pub fn set_threshold_secs(seconds: u64) -> Result<(), ZeroThreshold> {
+    if seconds == 0 {
+        return Err(ZeroThreshold);
+    }
+    THRESHOLD_NS.store(seconds.saturating_mul(1_000_000_000), Ordering::Relaxed);
+    Ok(())
+}
+
+fn arm_counter() {
+    let period = PERIOD_CYCLES.load(Ordering::Relaxed);
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    unsafe {
+        let mut select = Msr::new(IA32_PERFEVTSEL0);
+        select.write(0);
+        Msr::new(IA32_PMC0).write(period.wrapping_neg() & mask);
+        select.write(EVTSEL_UNHALTED_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+        if PERFMON_VERSION.load(Ordering::Relaxed) >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+            let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            global.write(global.read() | 1);
+        }
+    }
+    crate::lapic::set_perf_counter_nmi();
+}
+
+// The counter starts out negative, so a clear top bit means it wrapped.
+fn counter_overflowed() -> bool {
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    let top_bit = (mask >> 1) + 1;
+    unsafe { Msr::new(IA32_PMC0).read() & top_bit == 0 }
+}
+
+/// Records that the calling CPU's scheduler had nothing else waiting or
+/// switched tasks.
+pub fn touch_scheduler() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    LAST_SCHEDULE_NS
+        .get()
+        .store(crate::clock::nanos(), Ordering::Relaxed);
+    SOFT_REPORTED.get().store(false, Ordering::Relaxed);
+}
+
+/// Records progress after the calling CPU went without a tick, so the gap
+/// is not taken for a lockup.
+pub fn touch() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    LAST_TICK_NS
+        .get()
+        .store(crate::clock::nanos(), Ordering::Relaxed);
+    touch_scheduler();
+}
+
+/// Called from every scheduler tick with the interrupted context.
+pub fn on_tick(cpu: &PerCpu, context: &SavedContext) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = crate::clock::nanos();
+    let threshold = THRESHOLD_NS.load(Ordering::Relaxed);
+    let index = cpu.cpu_index();
+
+    LAST_TICK_NS.get().store(now, Ordering::Relaxed);
+    HARD_REPORTED.get().store(false, Ordering::Relaxed);
+
+    let last_schedule = LAST_SCHEDULE_NS.get().load(Ordering::Relaxed);
+    if now.saturating_sub(last_schedule) > threshold
+        && !SOFT_REPORTED.get().swap(true, Ordering::Relaxed)
+    {
+        report(Lockup::Soft, index, now - last_schedule, context);
+    }
+
+    if source() == NmiSource::PeerIpi {
+        check_peers(index, now, threshold);
+    }
+}
+
+/// Sends an NMI to every other CPU that has gone without a tick for
+/// `threshold` ns, asking it to report itself.
+fn check_peers(index: usize, now: u64, threshold: u64) {
+    for other in (0..MAX_CPUS).filter(|&other| other != index) {
+        let Some(peer) = percpu::get(other) else {
+            continue;
+        };
+        let last_tick = LAST_TICK_NS.get_for(other).load(Ordering::Relaxed);
+        if last_tick == 0
+            || now.saturating_sub(last_tick) <= threshold
+            || crate::idle::is_tickless(other)
+        {
+            continue;
+        }
+        if !HARD_REPORTED.get_for(other).swap(true, Ordering::Relaxed) {
+            DUMP_REQUESTED.get_for(other).store(true, Ordering::Release);
+            crate::lapic::send_nmi(peer.apic_id());
+        }
+    }
+}
+
+fn report(lockup: Lockup, cpu_index: usize, stalled_ns: u64, context: &SavedContext) {
+    debug::println!();
+    debug::println!(
+        "[WATCHDOG] {:?} lockup on CPU {}, stuck for {} ms",
+        lockup,
+        cpu_index,
+        stalled_ns / 1_000_000
+    );
+    debug::println!("rip: {:#018x}", context.rip);
+    debug::println!("task: {:?}", crate::multitask::current_task());
+    debug::println!("backtrace:");
+    debug::backtrace::print(context.rbp);
+}
+
+fn on_nmi(context: &SavedContext) {
+    crate::stats::count_vector(NMI_VECTOR);
+    let cpu = percpu::current();
+    let index = cpu.cpu_index();
+    let stalled_ns =
+        || crate::clock::nanos().saturating_sub(LAST_TICK_NS.get().load(Ordering::Relaxed));
+
+    if DUMP_REQUESTED.get().swap(false, Ordering::Acquire) {
+        report(Lockup::Hard, index, stalled_ns(), context);
+        return;
+    }
+
+    if source() == NmiSource::Hpet && crate::hpet::nmi_expired() {
+        let threshold = THRESHOLD_NS.load(Ordering::Relaxed);
+        let stalled = stalled_ns();
+        // A CPU halted with its tick stopped is not stalled.
+        if stalled > threshold
+            && !crate::idle::is_tickless(index)
+            && !HARD_REPORTED.get().swap(true, Ordering::Relaxed)
+        {
+            report(Lockup::Hard, index, stalled, context);
+        }
+        check_peers(index, crate::clock::nanos(), threshold);
+        return;
+    }
+
+    if source() == NmiSource::PerfCounter && counter_overflowed() {
+        arm_counter();
+        let stalled = stalled_ns();
+        if stalled > THRESHOLD_NS.load(Ordering::Relaxed)
+            && !HARD_REPORTED.get().swap(true, Ordering::Relaxed)
+        {
+            report(Lockup::Hard, index, stalled, context);
+        }
+        return;
+    }
+
+    debug::println!(
+        "[NMI] unexpected NMI on CPU {} at {:#018x}",
+        index,
+        context.rip
+    );
+}
+
+unsafe extern "C" {
+    fn nmi_entry();
+}
+
+pub fn nmi_handler_addr() -> u64 {
+    nmi_entry as *const () as usize as u64
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn nmi_dispatch(context: &SavedContext) {
+    // An NMI can land between an entry path's `swapgs` and the code that
+    // relies on it. The user GS base is never set, so a zero base means the
+    // kernel's is still parked in IA32_KERNEL_GS_BASE.
+    let swapped = GsBase::read().is_null();
+    if swapped {
+        unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+    }
+    on_nmi(context);
+    if swapped {
+        unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+    }
+}
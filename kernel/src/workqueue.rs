@@ -0,0 +1,119 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::multitask::{self, Priority, Thread};
+use crate::percpu;
+
+const INITIAL_CAPACITY: usize = 64;
+const WORKER_ID_BASE: u16 = 1000;
+// One bit each in `IDLE_WORKERS`.
+const MAX_WORKERS: usize = 64;
+
+enum Work {
+    Closure(Box<dyn FnOnce() + Send>),
+    Call(fn(usize), usize),
+}
+
+impl Work {
+    fn run(self) {
+        match self {
+            Work::Closure(closure) => closure(),
+            Work::Call(func, arg) => func(arg),
+        }
+    }
+}
+
+// Only ever locked with interrupts disabled.
+static QUEUE: Mutex<VecDeque<Work>> = Mutex::new(VecDeque::new());
+static WORKERS: Mutex<Vec<Thread>> = Mutex::new(Vec::new());
+// Workers that found the queue empty and are about to park or parked.
+static IDLE_WORKERS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts `workers` kernel threads, at most 64, that run queued work in FIFO
+/// order at high priority, so timer callbacks are not held up by busy
+/// threads. Requires the heap and the scheduler.
+pub fn init(workers: usize) {
+    interrupts::without_interrupts(|| {
+        QUEUE.lock().reserve(INITIAL_CAPACITY);
+
+        // Registered before they start, so a worker can be woken as soon as
+        // it parks.
+        let mut threads = WORKERS.lock();
+        for index in 0..workers.min(MAX_WORKERS) {
+            threads.push(
+                Thread::new(worker, WORKER_ID_BASE + index as u16)
+                    .with_name("worker")
+                    .with_priority(Priority::High),
+            );
+        }
+        for thread in threads.iter() {
+            thread.start();
+        }
+    });
+}
+
+/// Runs `work` on a worker thread. Must be called from task context, since
+/// it allocates.
+#[allow(dead_code)]
+pub fn queue(work: impl FnOnce() + Send + 'static) {
+    if percpu::in_interrupt() {
+        panic!("workqueue::queue called in interrupt context; use queue_call");
+    }
+    let work = Work::Closure(Box::new(work));
+    interrupts::without_interrupts(|| {
+        QUEUE.lock().push_back(work);
+        wake_worker();
+    });
+}
+
+/// Runs `func(arg)` on a worker thread. Never allocates: the queue does not
+/// grow past its capacity and waking a worker only queues it on a ready
+/// level with room reserved. Bottom halves can use it to hand off work that
+/// may block; returns false if the queue has no spare capacity.
+pub fn queue_call(func: fn(usize), arg: usize) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len() == queue.capacity() {
+            return false;
+        }
+        queue.push_back(Work::Call(func, arg));
+        drop(queue);
+        wake_worker();
+        true
+    })
+}
+
+// Called after queueing; workers set their idle bit before checking the
+// queue a last time, so either the worker sees the work or it gets woken.
+fn wake_worker() {
+    let idle = IDLE_WORKERS.load(Ordering::SeqCst);
+    if idle == 0 {
+        return;
+    }
+    let bit = 1 << idle.trailing_zeros();
+    if IDLE_WORKERS.fetch_and(!bit, Ordering::SeqCst) & bit != 0
+        && let Some(thread) = WORKERS.lock().get(bit.trailing_zeros() as usize)
+    {
+        thread.unpark();
+    }
+}
+
+fn worker(id: u16) {
+    let bit = 1u64 << (id - WORKER_ID_BASE);
+    loop {
+        if let Some(work) = interrupts::without_interrupts(|| QUEUE.lock().pop_front()) {
+            work.run();
+            continue;
+        }
+
+        IDLE_WORKERS.fetch_or(bit, Ordering::SeqCst);
+        if interrupts::without_interrupts(|| QUEUE.lock().is_empty()) {
+            multitask::park();
+        }
+        IDLE_WORKERS.fetch_and(!bit, Ordering::SeqCst);
+    }
+}